@@ -0,0 +1,40 @@
+use util::*;
+
+/// Puts and removes that `Store::write` applies together:  after a crash, either all of
+/// them happened or none did.  Within a batch, later operations on a key supercede
+/// earlier ones.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<(Buf, Mutation)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        return WriteBatch{ops: Vec::new()};
+    }
+
+    pub fn put(&mut self, key: &[u8], val: &[u8]) {
+        self.ops.push((key.to_vec(), Mutation::Set(val.to_vec())));
+    }
+
+    /// Unlike `Store::remove`, this doesn't check whether the key is present.
+    pub fn remove(&mut self, key: &[u8]) {
+        self.ops.push((key.to_vec(), Mutation::Delete));
+    }
+
+    pub fn len(&self) -> usize {
+        return self.ops.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.ops.is_empty();
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<(Buf, Mutation)> {
+        return self.ops;
+    }
+}
@@ -0,0 +1,376 @@
+use encoding::*;
+use error::*;
+use util::*;
+
+use std::sync::Arc;
+
+/* Block format:
+
+    [entry][entry]...[entry][u32 restart]...[u32 restart][u32 restart count]
+
+    with the entries in ascending order by key, and a restart for every RESTART_INTERVAL'th
+    entry (starting with the first), holding that entry's offset in the block.  Seeks binary
+    search the restarts, and stepping backwards scans forward from the previous restart.
+
+[entry] format:
+
+    [unsigned varint shared][unsigned varint unshared][unshared key bytes...][str value]
+
+    where the key is the first `shared` bytes of the previous entry's key, followed by the
+    `unshared` bytes.  Entries at restarts have nothing shared, so that decoding can start
+    there.
+*/
+
+const RESTART_INTERVAL: usize = 16;
+
+pub struct BlockBuilder {
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    count: usize,
+    last_key: Vec<u8>,
+}
+
+impl BlockBuilder {
+    pub fn new() -> BlockBuilder {
+        return BlockBuilder{buf: Vec::new(), restarts: Vec::new(), count: 0, last_key: Vec::new()};
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.count == 0;
+    }
+
+    // The size of the block, were we to finish it now.
+    pub fn size(&self) -> usize {
+        return self.buf.len() + 4 * self.restarts.len() + 4;
+    }
+
+    // This method has to be called in increasing key order.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        let mut shared: usize = 0;
+        if self.count.is_multiple_of(RESTART_INTERVAL) {
+            self.restarts.push(self.buf.len() as u32);
+        } else {
+            shared = self.last_key.iter().zip(key.iter()).take_while(|&(a, b)| a == b).count();
+        }
+        self.count += 1;
+        encode_uvarint(&mut self.buf, shared as u64);
+        encode_str(&mut self.buf, &key[shared..]);
+        encode_str(&mut self.buf, value);
+        self.last_key.truncate(shared);
+        self.last_key.extend_from_slice(&key[shared..]);
+    }
+
+    // Returns the finished block, and resets the builder.
+    pub fn finish(&mut self) -> Vec<u8> {
+        for &restart in self.restarts.iter() {
+            encode_u32(&mut self.buf, restart);
+        }
+        encode_u32(&mut self.buf, self.restarts.len() as u32);
+        self.restarts.clear();
+        self.count = 0;
+        self.last_key.clear();
+        return std::mem::take(&mut self.buf);
+    }
+}
+
+#[derive(Clone)]
+pub struct Block {
+    // The block is storage[start..start + len] -- storage is either a buffer holding just
+    // the block, or a whole memory-mapped table file.
+    storage: Arc<AsRef<[u8]> + Send + Sync>,
+    start: usize,
+    len: usize,
+    // Where the restart array starts, which is also where the entries end.
+    restarts_offset: usize,
+    num_restarts: usize,
+}
+
+impl Block {
+    pub fn new(data: Buf) -> Result<Block> {
+        let len: usize = data.len();
+        return Block::in_storage(Arc::new(data), 0, len);
+    }
+
+    // A block that borrows storage[start..start + len].
+    pub fn in_storage(storage: Arc<AsRef<[u8]> + Send + Sync>, start: usize, len: usize) -> Result<Block> {
+        let end: usize = start.checked_add(len).or_err("block past end of storage")?;
+        let data: &[u8] = (*storage).as_ref().get(start..end).or_err("block past end of storage")?;
+        if data.len() < 4 {
+            return mk_err("block too small");
+        }
+        let mut pos: usize = data.len() - 4;
+        let num_restarts: usize = decode_u32(data, &mut pos).or_err("cannot decode restart count")? as usize;
+        if num_restarts == 0 || num_restarts > (data.len() - 4) / 4 {
+            return mk_err("bad block restart count");
+        }
+        let restarts_offset: usize = data.len() - 4 - 4 * num_restarts;
+        return Ok(Block{
+            storage: storage,
+            start: start,
+            len: len,
+            restarts_offset: restarts_offset,
+            num_restarts: num_restarts,
+        });
+    }
+
+    fn data(&self) -> &[u8] {
+        return &(*self.storage).as_ref()[self.start..self.start + self.len];
+    }
+
+    // The size of the block's contents, in bytes.
+    pub fn size(&self) -> usize {
+        return self.len;
+    }
+
+    fn restart(&self, i: usize) -> Result<usize> {
+        let mut pos: usize = self.restarts_offset + 4 * i;
+        let restart: usize = decode_u32(self.data(), &mut pos).or_err("cannot decode restart")? as usize;
+        if restart >= self.restarts_offset {
+            return mk_err("bad block restart");
+        }
+        return Ok(restart);
+    }
+}
+
+// An entry's value, as a range of the block's data, and the offset of the next entry.
+struct DecodedEntry {
+    value: (usize, usize),
+    next: usize,
+}
+
+pub struct BlockIter {
+    block: Block,
+    // The offset of the current entry, or None if we've stepped off either end.
+    current: Option<usize>,
+    // The current entry's key, which (with prefix compression) we have to piece together.
+    key: Vec<u8>,
+    entry: DecodedEntry,
+}
+
+impl BlockIter {
+    // The iterator starts off invalid -- it has to be positioned with a seek.
+    pub fn new(block: Block) -> BlockIter {
+        return BlockIter{block: block, current: None, key: Vec::new(), entry: DecodedEntry{value: (0, 0), next: 0}};
+    }
+
+    // Decodes the entry at pos, whose key shares a prefix with `key`, the previous entry's key,
+    // turning `key` into the entry's key.
+    fn decode_entry(&self, pos: usize, key: &mut Vec<u8>) -> Result<DecodedEntry> {
+        let entries: &[u8] = &self.block.data()[..self.block.restarts_offset];
+        let mut p: usize = pos;
+        let shared: usize = try_into_size(decode_uvarint(entries, &mut p).or_err("cannot decode shared key length")?)
+            .or_err("shared key length too big")?;
+        if shared > key.len() {
+            return mk_err("key shares more than the previous key");
+        }
+        let unshared: &[u8] = observe_str(entries, &mut p).or_err("cannot decode key")?;
+        key.truncate(shared);
+        key.extend_from_slice(unshared);
+        let value_len: usize = try_into_size(decode_uvarint(entries, &mut p).or_err("cannot decode value length")?)
+            .or_err("value length too big")?;
+        if value_len > entries.len() - p {
+            return mk_err("value past end of block");
+        }
+        let value = (p, p + value_len);
+        return Ok(DecodedEntry{value: value, next: p + value_len});
+    }
+
+    // Moves to the entry at pos, which has to be a restart or the entry after the current one.
+    fn set_current(&mut self, pos: usize) -> Result<()> {
+        if pos >= self.block.restarts_offset {
+            self.current = None;
+        } else {
+            let mut key: Vec<u8> = std::mem::take(&mut self.key);
+            let entry = self.decode_entry(pos, &mut key);
+            self.key = key;
+            self.entry = entry?;
+            self.current = Some(pos);
+        }
+        return Ok(());
+    }
+
+    fn seek_to_restart(&mut self, i: usize) -> Result<()> {
+        let restart = self.block.restart(i)?;
+        self.key.clear();
+        return self.set_current(restart);
+    }
+
+    // The key of the entry at the i'th restart.
+    fn restart_key(&self, i: usize, key: &mut Vec<u8>) -> Result<()> {
+        key.clear();
+        self.decode_entry(self.block.restart(i)?, key)?;
+        return Ok(());
+    }
+
+    pub fn valid(&self) -> bool {
+        return self.current.is_some();
+    }
+
+    pub fn key(&self) -> Option<&[u8]> {
+        return self.current.map(|_| &self.key[..]);
+    }
+
+    pub fn value(&self) -> Option<&[u8]> {
+        return self.current.map(|_| &self.block.data()[self.entry.value.0..self.entry.value.1]);
+    }
+
+    pub fn seek_to_first(&mut self) -> Result<()> {
+        return self.seek_to_restart(0);
+    }
+
+    pub fn seek_to_last(&mut self) -> Result<()> {
+        let last: usize = self.block.num_restarts - 1;
+        self.seek_to_restart(last)?;
+        while self.entry.next < self.block.restarts_offset {
+            let next = self.entry.next;
+            self.set_current(next)?;
+        }
+        return Ok(());
+    }
+
+    // Positions the iterator at the first key >= target.
+    pub fn seek(&mut self, target: &[u8]) -> Result<()> {
+        // Find the last restart whose key is < target.  (Or the first restart.)
+        let mut low: usize = 0;
+        let mut high: usize = self.block.num_restarts;
+        let mut key = Vec::<u8>::new();
+        while high - low > 1 {
+            let mid: usize = low + (high - low) / 2;
+            self.restart_key(mid, &mut key)?;
+            if &key[..] < target {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        self.seek_to_restart(low)?;
+        while let Some(key) = self.key() {
+            if key >= target {
+                break;
+            }
+            let next = self.entry.next;
+            self.set_current(next)?;
+        }
+        return Ok(());
+    }
+
+    pub fn next(&mut self) -> Result<()> {
+        if self.current.is_none() {
+            return invalid_arg_err("next called on invalid BlockIter");
+        }
+        let next = self.entry.next;
+        return self.set_current(next);
+    }
+
+    pub fn prev(&mut self) -> Result<()> {
+        let current: usize = self.current.or_invalid_arg("prev called on invalid BlockIter")?;
+        // Find the last restart before the current entry, and scan forward from there.
+        let mut r: usize = self.block.num_restarts;
+        loop {
+            if r == 0 {
+                // We were at the first entry.
+                self.current = None;
+                return Ok(());
+            }
+            r -= 1;
+            if self.block.restart(r)? < current {
+                break;
+            }
+        }
+        self.seek_to_restart(r)?;
+        while self.entry.next < current {
+            let next = self.entry.next;
+            self.set_current(next)?;
+        }
+        if self.entry.next != current {
+            return mk_err("block entries don't line up");
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: usize) -> Vec<u8> {
+        return format!("{:05}", i * 2).into_bytes();
+    }
+
+    fn make_block(n: usize) -> Block {
+        let mut builder = BlockBuilder::new();
+        for i in 0..n {
+            builder.add(&key(i), format!("value-{}", i).as_bytes());
+        }
+        return Block::new(builder.finish()).unwrap();
+    }
+
+    #[test]
+    fn forward_and_backward() {
+        let n = 100;
+        let mut iter = BlockIter::new(make_block(n));
+        iter.seek_to_first().unwrap();
+        for i in 0..n {
+            assert_eq!(Some(&key(i) as &[u8]), iter.key());
+            assert_eq!(Some(format!("value-{}", i).as_bytes()), iter.value());
+            iter.next().unwrap();
+        }
+        assert!(!iter.valid());
+
+        iter.seek_to_last().unwrap();
+        for i in (0..n).rev() {
+            assert_eq!(Some(&key(i) as &[u8]), iter.key());
+            iter.prev().unwrap();
+        }
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn shared_prefixes() {
+        let keys: Vec<Vec<u8>> = (0..100).map(|i| format!("tenant/entity/{:03}/{:03}", i / 3, i).into_bytes()).collect();
+        let mut builder = BlockBuilder::new();
+        for key in keys.iter() {
+            builder.add(key, b"v");
+        }
+        let full_size: usize = keys.iter().map(|k| k.len()).sum();
+        let block = Block::new(builder.finish()).unwrap();
+        assert!(block.size() < full_size / 2);
+
+        let mut iter = BlockIter::new(block);
+        iter.seek_to_last().unwrap();
+        for key in keys.iter().rev() {
+            assert_eq!(Some(&key[..]), iter.key());
+            iter.prev().unwrap();
+        }
+        for key in keys.iter() {
+            iter.seek(key).unwrap();
+            assert_eq!(Some(&key[..]), iter.key());
+        }
+    }
+
+    #[test]
+    fn seek() {
+        let n = 100;
+        let mut iter = BlockIter::new(make_block(n));
+        for i in 0..n {
+            iter.seek(&key(i)).unwrap();
+            assert_eq!(Some(&key(i) as &[u8]), iter.key());
+            // Odd numbers aren't in the block.
+            iter.seek(format!("{:05}", i * 2 + 1).as_bytes()).unwrap();
+            if i + 1 < n {
+                assert_eq!(Some(&key(i + 1) as &[u8]), iter.key());
+            } else {
+                assert!(!iter.valid());
+            }
+        }
+        iter.seek(b"").unwrap();
+        assert_eq!(Some(&key(0) as &[u8]), iter.key());
+    }
+
+    #[test]
+    fn bad_bounds() {
+        let storage: Arc<Vec<u8>> = Arc::new(vec![0u8; 16]);
+        assert!(Block::in_storage(storage.clone(), 8, 16).is_err());
+        assert!(Block::in_storage(storage, 8, usize::MAX).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use fnv;
+use std::hash::Hasher;
+
+/* Bloom filter format:
+
+    [bits...][u8 number of probes]
+
+An empty filter (zero bytes) matches every key.  We set probe bits with double hashing,
+deriving every probe from one 64-bit hash of the key.
+*/
+
+// More probes than this and we'd be better off with more bits.
+const MAX_PROBES: usize = 30;
+
+fn key_hash(key: &[u8]) -> u64 {
+    let mut h = fnv::FnvHasher::default();
+    h.write(key);
+    return h.finish();
+}
+
+pub struct FilterBuilder {
+    bits_per_key: usize,
+    hashes: Vec<u64>,
+}
+
+impl FilterBuilder {
+    // A bits_per_key of zero builds empty filters.
+    pub fn new(bits_per_key: usize) -> FilterBuilder {
+        return FilterBuilder{bits_per_key: bits_per_key, hashes: Vec::new()};
+    }
+
+    pub fn add_key(&mut self, key: &[u8]) {
+        if self.bits_per_key > 0 {
+            self.hashes.push(key_hash(key));
+        }
+    }
+
+    pub fn finish(&self) -> Vec<u8> {
+        if self.bits_per_key == 0 {
+            return Vec::new();
+        }
+        // k = ln(2) * bits_per_key minimizes the false positive rate.
+        let probes: usize = (self.bits_per_key * 69 / 100).clamp(1, MAX_PROBES);
+        let bytes: usize = (self.hashes.len() * self.bits_per_key).max(64).div_ceil(8);
+        let bits: u64 = bytes as u64 * 8;
+
+        let mut ret = vec![0u8; bytes];
+        for &hash in self.hashes.iter() {
+            let mut h: u64 = hash;
+            let delta: u64 = h.rotate_left(31);
+            for _ in 0..probes {
+                let bit: u64 = h % bits;
+                ret[(bit / 8) as usize] |= 1 << (bit % 8);
+                h = h.wrapping_add(delta);
+            }
+        }
+        ret.push(probes as u8);
+        return ret;
+    }
+}
+
+// Returns false only if the key was definitely not added to the filter.
+pub fn filter_may_contain(filter: &[u8], key: &[u8]) -> bool {
+    if filter.len() < 2 {
+        return true;
+    }
+    let probes: usize = filter[filter.len() - 1] as usize;
+    if probes > MAX_PROBES {
+        // Maybe some future encoding.  Don't filter anything.
+        return true;
+    }
+    let bits: u64 = (filter.len() as u64 - 1) * 8;
+    let mut h: u64 = key_hash(key);
+    let delta: u64 = h.rotate_left(31);
+    for _ in 0..probes {
+        let bit: u64 = h % bits;
+        if filter[(bit / 8) as usize] & (1 << (bit % 8)) == 0 {
+            return false;
+        }
+        h = h.wrapping_add(delta);
+    }
+    return true;
+}
+
+#[cfg(test)]
+mod tests {
+    fn key(i: usize) -> Vec<u8> {
+        return format!("key-{}", i).into_bytes();
+    }
+
+    #[test]
+    fn no_false_negatives() {
+        let mut builder = super::FilterBuilder::new(10);
+        for i in 0..1000 {
+            builder.add_key(&key(i));
+        }
+        let filter: Vec<u8> = builder.finish();
+        for i in 0..1000 {
+            assert!(super::filter_may_contain(&filter, &key(i)));
+        }
+
+        // With 10 bits per key, we expect about 1% false positives.
+        let false_positives = (1000..11000).filter(|&i| super::filter_may_contain(&filter, &key(i))).count();
+        assert!(false_positives < 300);
+    }
+
+    #[test]
+    fn empty() {
+        let filter: Vec<u8> = super::FilterBuilder::new(0).finish();
+        assert!(filter.is_empty());
+        assert!(super::filter_may_contain(&filter, &key(1)));
+
+        // A filter with no keys in it matches nothing.
+        let filter: Vec<u8> = super::FilterBuilder::new(10).finish();
+        assert!(!super::filter_may_contain(&filter, &key(1)));
+    }
+}
@@ -0,0 +1,191 @@
+use block::*;
+use disk::OpenTable;
+use util::*;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Block cache hit and miss counts, from `Store::block_cache_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// A least-recently-used cache holding values whose charges add up to at most `capacity`.
+struct Lru<K: Ord + Copy, V: Clone> {
+    capacity: usize,
+    usage: usize,
+    // Every cached value, with its charge and the tick at which it was last used.
+    entries: BTreeMap<K, (V, usize, u64)>,
+    // The keys of cached values, by the tick at which they were last used.
+    lru: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Ord + Copy, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Lru<K, V> {
+        return Lru{capacity: capacity, usage: 0, entries: BTreeMap::new(), lru: BTreeMap::new(), tick: 0};
+    }
+
+    fn get(&mut self, key: K) -> Option<V> {
+        if let Some(&mut (ref value, _, ref mut last_used)) = self.entries.get_mut(&key) {
+            self.lru.remove(last_used);
+            self.tick += 1;
+            *last_used = self.tick;
+            self.lru.insert(self.tick, key);
+            return Some(value.clone());
+        }
+        return None;
+    }
+
+    fn insert(&mut self, key: K, value: V, charge: usize) {
+        if charge > self.capacity || self.entries.contains_key(&key) {
+            return;
+        }
+        self.usage += charge;
+        self.tick += 1;
+        self.entries.insert(key, (value, charge, self.tick));
+        self.lru.insert(self.tick, key);
+        while self.usage > self.capacity {
+            let oldest: u64 = *self.lru.keys().next().expect("cache usage without entries");
+            let evicted: K = self.lru[&oldest];
+            self.remove(evicted);
+        }
+    }
+
+    fn remove(&mut self, key: K) {
+        if let Some((_, charge, last_used)) = self.entries.remove(&key) {
+            self.lru.remove(&last_used);
+            self.usage -= charge;
+        }
+    }
+
+    // Removes every key in [low, high).
+    fn remove_range(&mut self, low: K, high: K) {
+        let keys: Vec<K> = self.entries.range(low..high).map(|(&key, _)| key).collect();
+        for key in keys {
+            self.remove(key);
+        }
+    }
+}
+
+// An LRU cache of table data blocks, keyed by table id and block offset, holding at
+// most `capacity` bytes of block contents.
+pub struct BlockCache {
+    blocks: Lru<(TableId, u64), Block>,
+    stats: CacheStats,
+}
+
+pub type SharedBlockCache = Arc<Mutex<BlockCache>>;
+
+impl BlockCache {
+    // A capacity of zero caches nothing.
+    pub fn new(capacity: usize) -> BlockCache {
+        return BlockCache{blocks: Lru::new(capacity), stats: CacheStats::default()};
+    }
+
+    pub fn get(&mut self, table_id: TableId, offset: u64) -> Option<Block> {
+        let ret = self.blocks.get((table_id, offset));
+        if ret.is_some() {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+        }
+        return ret;
+    }
+
+    pub fn insert(&mut self, table_id: TableId, offset: u64, block: Block) {
+        let size: usize = block.size();
+        self.blocks.insert((table_id, offset), block, size);
+    }
+
+    // Drops every block of the table.  Table ids can get reused after a toc rewrite, so a
+    // deleted table's blocks mustn't outlive it.
+    pub fn remove_table(&mut self, table_id: TableId) {
+        self.blocks.remove_range((table_id, 0), (TableId(table_id.0 + 1), 0));
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        return self.stats;
+    }
+}
+
+// An LRU cache of open table files, with their footers and index blocks read, holding
+// at most `capacity` tables.
+pub struct TableCache {
+    tables: Lru<TableId, Arc<OpenTable>>,
+    // Whether tables get opened with their files memory-mapped.
+    mmap: bool,
+}
+
+pub type SharedTableCache = Arc<Mutex<TableCache>>;
+
+impl TableCache {
+    // A capacity of zero caches nothing.
+    pub fn new(capacity: usize, mmap: bool) -> TableCache {
+        return TableCache{tables: Lru::new(capacity), mmap: mmap};
+    }
+
+    pub fn mmap(&self) -> bool {
+        return self.mmap;
+    }
+
+    pub fn get(&mut self, table_id: TableId) -> Option<Arc<OpenTable>> {
+        return self.tables.get(table_id);
+    }
+
+    pub fn insert(&mut self, table_id: TableId, table: Arc<OpenTable>) {
+        self.tables.insert(table_id, table, 1);
+    }
+
+    // Like BlockCache::remove_table, this has to be called when a table is deleted.  (Iterators
+    // still holding the table keep its file open.)
+    pub fn remove(&mut self, table_id: TableId) {
+        self.tables.remove(table_id);
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        return self.tables.entries.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_of_size(n: usize) -> Block {
+        let mut builder = BlockBuilder::new();
+        builder.add(b"k", &vec![0u8; n]);
+        return Block::new(builder.finish()).unwrap();
+    }
+
+    #[test]
+    fn lru() {
+        let size: usize = block_of_size(100).size();
+        let mut cache = BlockCache::new(3 * size);
+        for i in 0..3 {
+            cache.insert(TableId(1), i * 1000, block_of_size(100));
+        }
+        assert!(cache.get(TableId(1), 0).is_some());
+        // Evicts offset 1000, the least recently used.
+        cache.insert(TableId(2), 0, block_of_size(100));
+        assert!(cache.get(TableId(1), 1000).is_none());
+        assert!(cache.get(TableId(1), 0).is_some());
+        assert!(cache.get(TableId(1), 2000).is_some());
+        assert!(cache.get(TableId(2), 0).is_some());
+        assert_eq!(CacheStats{hits: 4, misses: 1}, cache.stats());
+
+        cache.remove_table(TableId(1));
+        assert!(cache.get(TableId(1), 0).is_none());
+        assert!(cache.get(TableId(2), 0).is_some());
+        assert_eq!(size, cache.blocks.usage);
+
+        // Nothing fits in a zero-capacity cache.
+        let mut cache = BlockCache::new(0);
+        cache.insert(TableId(1), 0, block_of_size(100));
+        assert!(cache.get(TableId(1), 0).is_none());
+    }
+}
@@ -0,0 +1,254 @@
+use cache::*;
+use disk::*;
+use error::*;
+use iter::*;
+use memstore::*;
+use options::Options;
+use toc::*;
+use util::*;
+
+use std::collections::Bound;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+
+// Work for the compaction thread.  Jobs only write table files:  the store records the new
+// tables in the toc, and deletes the ones they replace, once it collects the job's result.
+// That way only the store's own thread touches the toc.
+pub enum Job {
+    // Writes the oldest immutable memstore to a level zero table.
+    Flush(Arc<MemStore>),
+    // Merges the tables, which are in order of precedence, into new tables in the level.
+    Merge{tables: Vec<TableInfo>, level: LevelNumber},
+}
+
+pub struct Finished {
+    pub additions: Vec<TableInfo>,
+    pub removals: Vec<TableId>,
+    // The bloom filter of each added table.
+    pub filters: Vec<(TableId, Buf)>,
+    // Whether the job was a Flush.
+    pub flushed: bool,
+}
+
+// The compaction thread, which runs one job at a time.
+pub struct Compactor {
+    // In an option so that we can hang up before joining the thread.
+    jobs: Option<mpsc::Sender<(Job, u64, Vec<SeqNum>)>>,
+    // In a mutex only so that the store can be Sync.
+    results: Mutex<mpsc::Receiver<Result<Finished>>>,
+    thread: Option<thread::JoinHandle<()>>,
+    // Whether there's a job whose result we haven't collected.
+    busy: bool,
+    // How many jobs we've started, and (counted by the thread) how many it's finished.
+    started: u64,
+    done: Arc<(Mutex<u64>, Condvar)>,
+}
+
+// Waits for a job to finish, without needing the store.  Writers use this to wait out
+// stalls while other threads use the store.
+pub struct JobWaiter {
+    done: Arc<(Mutex<u64>, Condvar)>,
+    job: u64,
+}
+
+impl JobWaiter {
+    pub fn wait(&self) {
+        let &(ref count, ref cond) = &*self.done;
+        let mut finished = count.lock().unwrap();
+        while *finished < self.job {
+            finished = cond.wait(finished).unwrap();
+        }
+    }
+}
+
+// Once the thread exits, no more jobs will finish, so this wakes any waiters for good.
+struct DoneOnExit(Arc<(Mutex<u64>, Condvar)>);
+
+impl Drop for DoneOnExit {
+    fn drop(&mut self) {
+        let &(ref count, ref cond) = &*self.0;
+        if let Ok(mut finished) = count.lock() {
+            *finished = u64::max_value();
+        }
+        cond.notify_all();
+    }
+}
+
+impl Compactor {
+    pub fn spawn(dir: &str, threshold: usize, options: &Options) -> Result<Compactor> {
+        let (jobs, job_receiver) = mpsc::channel::<(Job, u64, Vec<SeqNum>)>();
+        let (result_sender, results) = mpsc::channel::<Result<Finished>>();
+        let dir: String = dir.to_string();
+        let options: Options = options.clone();
+        let done = Arc::new((Mutex::new(0u64), Condvar::new()));
+        let thread_done = DoneOnExit(done.clone());
+        let thread = thread::Builder::new().name("nihdb-compaction".to_string()).spawn(move || {
+            for (job, next_table_id, snapshots) in job_receiver.iter() {
+                let result = run_job(&dir, threshold, &options, job, next_table_id, &snapshots);
+                if result_sender.send(result).is_err() {
+                    return;
+                }
+                let &(ref count, ref cond) = &*thread_done.0;
+                *count.lock().unwrap() += 1;
+                cond.notify_all();
+            }
+        })?;
+        return Ok(Compactor{
+            jobs: Some(jobs),
+            results: Mutex::new(results),
+            thread: Some(thread),
+            busy: false,
+            started: 0,
+            done: done,
+        });
+    }
+
+    pub fn busy(&self) -> bool {
+        return self.busy;
+    }
+
+    // Returns a waiter for the running job, if there is one.
+    pub fn waiter(&self) -> Option<JobWaiter> {
+        if !self.busy {
+            return None;
+        }
+        return Some(JobWaiter{done: self.done.clone(), job: self.started});
+    }
+
+    // The job's new tables get numbered from next_table_id up, and keep only the versions
+    // that the live snapshots (in ascending order) or readers of the latest state can read.
+    // (Snapshots taken later see the newest versions, which are always kept.)  Only one job
+    // runs at a time.
+    pub fn start(&mut self, job: Job, next_table_id: u64, snapshots: Vec<SeqNum>) -> Result<()> {
+        assert!(!self.busy);
+        if self.jobs.as_ref().expect("compactor hung up").send((job, next_table_id, snapshots)).is_err() {
+            return mk_err("compaction thread died");
+        }
+        self.busy = true;
+        self.started += 1;
+        return Ok(());
+    }
+
+    // Returns the running job's result, if it's done (or once it is, if `wait`).  Returns
+    // None if no job is running.
+    pub fn finished(&mut self, wait: bool) -> Option<Result<Finished>> {
+        if !self.busy {
+            return None;
+        }
+        let results = self.results.lock().unwrap();
+        let result: Result<Finished> = if wait {
+            match results.recv() {
+                Ok(result) => result,
+                Err(_) => mk_err("compaction thread died"),
+            }
+        } else {
+            match results.try_recv() {
+                Ok(result) => result,
+                Err(mpsc::TryRecvError::Empty) => return None,
+                Err(mpsc::TryRecvError::Disconnected) => mk_err("compaction thread died"),
+            }
+        };
+        self.busy = false;
+        return Some(result);
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // Hanging up makes the thread exit once it's done with its job.
+        self.jobs = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run_job(dir: &str, threshold: usize, options: &Options, job: Job, next_table_id: u64, snapshots: &[SeqNum]
+) -> Result<Finished> {
+    let ret: Finished = match job {
+        Job::Flush(ms) => {
+            let mut ret = Finished{additions: Vec::new(), removals: Vec::new(), filters: Vec::new(), flushed: true};
+            if !ms.entries.is_empty() {
+                let table_id = TableId(next_table_id);
+                let (file_size, smallest, biggest, filter)
+                    = flush_to_disk(dir, table_id, &ms, snapshots, options.bloom_bits_per_key, options.compression)?;
+                ret.filters.push((table_id, filter));
+                ret.additions.push(TableInfo{
+                    id: table_id,
+                    level: 0,
+                    file_size: file_size,
+                    smallest_key: smallest,
+                    biggest_key: biggest,
+                });
+            }
+            ret
+        },
+        Job::Merge{tables, level} => {
+            merge_tables(dir, threshold, options, &tables, level, next_table_id, snapshots)?
+        },
+    };
+    // The toc mustn't reference tables whose directory entries could get lost.
+    sync_directory(dir)?;
+    return Ok(ret);
+}
+
+fn merge_tables(dir: &str, threshold: usize, options: &Options, tables: &[TableInfo], level: LevelNumber,
+                next_table_id: u64, snapshots: &[SeqNum]) -> Result<Finished> {
+    let mut ret = Finished{additions: Vec::new(), removals: Vec::new(), filters: Vec::new(), flushed: false};
+    // Compactions read each block once, so there's no use caching anything, let alone
+    // pushing readers' blocks out of the store's caches.
+    let table_cache: SharedTableCache = Arc::new(Mutex::new(TableCache::new(0, false)));
+    let block_cache: SharedBlockCache = Arc::new(Mutex::new(BlockCache::new(0)));
+
+    // NOTE: We might want a smarter iterator for the lower level --
+    // open only one table file at a time, instead of generically
+    // merging the non-overlapping tables together.
+    let mut iters: Vec<Box<MutationIterator>> = Vec::new();
+    let interval = Interval{lower: Bound::Unbounded, upper: Bound::Unbounded};
+    for ti in tables {
+        iters.push(Box::new(TableIterator::make(
+            dir, ti, &interval, Direction::Forward, SeqNum::max_value(), &table_cache, &block_cache)?));
+    }
+    let mut iter = MergeIterator::make(iters, Direction::Forward)?;
+
+    let mut table_id = TableId(next_table_id);
+    'outer: loop {
+        let mut builder = TableBuilder::new(options.bloom_bits_per_key, options.compression);
+        'inner: loop {
+            // NOTE: It would be nice to avoid cloning the key here.
+            if let Some(key) = iter.current_key()?.map(|x| x.to_vec()) {
+                let mut versions: Versions = iter.current_versions()?;
+                retain_readable(&mut versions, snapshots);
+                builder.add_versions(&key, &versions);
+                iter.step()?;
+                if builder.lowerbound_file_size() > threshold {
+                    break 'inner;
+                }
+            } else {
+                if builder.is_empty() {
+                    break 'outer;
+                } else {
+                    break 'inner;
+                }
+            }
+        }
+
+        // We've got a non-empty builder.  Flush it to disk.
+        let (file_size, smallest, biggest, filter) = write_table(dir, table_id, builder)?;
+        ret.filters.push((table_id, filter));
+        ret.additions.push(TableInfo{
+            id: table_id,
+            level: level,
+            file_size: file_size,
+            smallest_key: smallest,
+            biggest_key: biggest,
+        });
+        table_id = TableId(table_id.0 + 1);
+    }
+
+    ret.removals = tables.iter().map(|ti| ti.id).collect();
+    return Ok(ret);
+}
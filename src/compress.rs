@@ -0,0 +1,179 @@
+use encoding::*;
+use error::*;
+use options::Compression;
+
+// A codec for table data blocks.  Each table records the id of the codec its data blocks were
+// compressed with, so to add a codec, implement this and give it a new id in compressor_by_id.
+// Open tables hold their codec, and they get shared across threads.
+pub trait Compressor: Sync {
+    // Recorded in table footers.  Never reuse one.
+    fn id(&self) -> u32;
+    fn compress(&self, input: &[u8]) -> Vec<u8>;
+    fn decompress(&self, input: &[u8]) -> Result<Vec<u8>>;
+}
+
+pub struct NoCompressor;
+
+impl Compressor for NoCompressor {
+    fn id(&self) -> u32 { 0 }
+
+    fn compress(&self, input: &[u8]) -> Vec<u8> {
+        return input.to_vec();
+    }
+
+    fn decompress(&self, input: &[u8]) -> Result<Vec<u8>> {
+        return Ok(input.to_vec());
+    }
+}
+
+/* LzCompressor format:
+
+    [unsigned varint decompressed length][sequence][sequence]...[sequence]
+
+[sequence] format:
+
+    [unsigned varint literal length][literal bytes...][unsigned varint match length][unsigned varint offset]
+
+    which appends the literal bytes, then copies match length bytes starting offset bytes back
+    from the end of the output (possibly overlapping what's being appended).  The last sequence
+    stops after its literal bytes, once the output is at its decompressed length.
+*/
+pub struct LzCompressor;
+
+const LZ_MIN_MATCH: usize = 4;
+const LZ_HASH_BITS: u32 = 12;
+
+fn lz_hash(four: &[u8]) -> usize {
+    let x: u32 = four[0] as u32 | (four[1] as u32) << 8 | (four[2] as u32) << 16 | (four[3] as u32) << 24;
+    return (x.wrapping_mul(2654435761) >> (32 - LZ_HASH_BITS)) as usize;
+}
+
+impl Compressor for LzCompressor {
+    fn id(&self) -> u32 { 1 }
+
+    fn compress(&self, input: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::<u8>::new();
+        encode_uvarint(&mut ret, input.len() as u64);
+        // The last position (plus one, zero meaning none) at which we saw each 4-byte hash.
+        let mut table = vec![0usize; 1 << LZ_HASH_BITS];
+        let mut literal_start: usize = 0;
+        let mut i: usize = 0;
+        while i + LZ_MIN_MATCH <= input.len() {
+            let h: usize = lz_hash(&input[i..i + LZ_MIN_MATCH]);
+            let candidate: usize = table[h];
+            table[h] = i + 1;
+            if candidate == 0 || input[candidate - 1..candidate - 1 + LZ_MIN_MATCH] != input[i..i + LZ_MIN_MATCH] {
+                i += 1;
+                continue;
+            }
+            let start: usize = candidate - 1;
+            let mut length: usize = LZ_MIN_MATCH;
+            while i + length < input.len() && input[start + length] == input[i + length] {
+                length += 1;
+            }
+            encode_str(&mut ret, &input[literal_start..i]);
+            encode_uvarint(&mut ret, length as u64);
+            encode_uvarint(&mut ret, (i - start) as u64);
+            i += length;
+            literal_start = i;
+        }
+        encode_str(&mut ret, &input[literal_start..]);
+        return ret;
+    }
+
+    fn decompress(&self, input: &[u8]) -> Result<Vec<u8>> {
+        let mut pos: usize = 0;
+        let length: usize = try_into_size(decode_uvarint(input, &mut pos).or_err("cannot decode lz length")?)
+            .or_err("lz length too big")?;
+        // Don't trust the length too much with the allocation.
+        let mut ret = Vec::<u8>::with_capacity(length.min(input.len() * 8));
+        loop {
+            let literals: &[u8] = observe_str(input, &mut pos).or_err("cannot decode lz literals")?;
+            ret.extend_from_slice(literals);
+            if ret.len() >= length {
+                break;
+            }
+            let match_length: usize = try_into_size(decode_uvarint(input, &mut pos).or_err("cannot decode lz match")?)
+                .or_err("lz match too long")?;
+            let offset: usize = try_into_size(decode_uvarint(input, &mut pos).or_err("cannot decode lz offset")?)
+                .or_err("lz offset too big")?;
+            if offset == 0 || offset > ret.len() || match_length > length - ret.len() {
+                return mk_err("bad lz match");
+            }
+            let start: usize = ret.len() - offset;
+            for j in 0..match_length {
+                let b: u8 = ret[start + j];
+                ret.push(b);
+            }
+        }
+        if ret.len() != length || pos != input.len() {
+            return mk_err("lz data has the wrong length");
+        }
+        return Ok(ret);
+    }
+}
+
+static NO_COMPRESSOR: NoCompressor = NoCompressor;
+static LZ_COMPRESSOR: LzCompressor = LzCompressor;
+
+pub fn compressor(compression: Compression) -> &'static Compressor {
+    return match compression {
+        Compression::None => &NO_COMPRESSOR,
+        Compression::Lz => &LZ_COMPRESSOR,
+    };
+}
+
+pub fn compressor_by_id(id: u32) -> Option<&'static Compressor> {
+    return match id {
+        0 => Some(&NO_COMPRESSOR),
+        1 => Some(&LZ_COMPRESSOR),
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    fn round_trip(input: &[u8]) -> usize {
+        let c = LzCompressor;
+        let compressed: Vec<u8> = c.compress(input);
+        assert_eq!(input, &c.decompress(&compressed).unwrap()[..]);
+        return compressed.len();
+    }
+
+    #[test]
+    fn lz() {
+        assert_eq!(2, round_trip(b""));
+        round_trip(b"abc");
+        round_trip(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+
+        let mut json = Vec::<u8>::new();
+        for i in 0..100 {
+            json.extend_from_slice(format!("{{\"id\": {}, \"name\": \"entity-{}\", \"active\": true}}", i, i).as_bytes());
+        }
+        assert!(round_trip(&json) < json.len() / 3);
+
+        // Random bytes don't compress, but shouldn't grow much either.
+        let mut rng = ::rand::thread_rng();
+        let random: Vec<u8> = (0..4096).map(|_| rng.gen::<u8>()).collect();
+        assert!(round_trip(&random) < random.len() + 16);
+    }
+
+    #[test]
+    fn lz_malformed() {
+        let c = LzCompressor;
+        let compressed: Vec<u8> = c.compress(b"abcdabcdabcdabcd");
+        for len in 0..compressed.len() {
+            assert!(c.decompress(&compressed[..len]).is_err());
+        }
+        // A match reaching back before the start of the output.
+        let mut bad = Vec::<u8>::new();
+        encode_uvarint(&mut bad, 8);
+        encode_str(&mut bad, b"ab");
+        encode_uvarint(&mut bad, 6);
+        encode_uvarint(&mut bad, 3);
+        assert!(c.decompress(&bad).is_err());
+    }
+}
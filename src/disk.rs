@@ -1,58 +1,154 @@
+use block::*;
+use bloom::*;
+use cache::*;
+use compress::*;
 use encoding::*;
 use error::*;
 use iter::*;
 use memstore::*;
+use mmap::*;
+use options::Compression;
 use util::*;
 use toc::*;
 
-use owning_ref::*;
+use crc;
 use std;
 use std::collections::Bound;
-use std::cmp::*;
-use std::io::Read;
-use std::io::Seek;
 use std::io::Write;
-use std::rc::Rc;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
 
 
-/* .tab file format:
+/* .tab file format (version 5):
 
-    [values...][keys...][8-byte KEY_OFFSET]
-               ^
-               KEY_OFFSET
+    [data block][data block]...[data block][filter block][index block][footer]
 
-[values...] format:
+    where each block is followed by a u32 crc32c of its contents.  Every byte we read from
+    a table, other than the footer, is in some block and gets checked.
 
-    [value][value]...[value]
+[data block] format:
 
-    where each value is either [u8 = 0][str] or [u8 = 1].
+    a block (see block.rs) mapping keys to [versions], in ascending order by key, with
+    each block (except the last) holding about DATA_BLOCK_SIZE bytes before compression.
+    The block gets compressed with the table's codec (see compress.rs), and the checksum
+    covers the compressed bytes.
+
+[versions] format:
+
+    [unsigned varint N][unsigned varint seq][mutation]...[unsigned varint seq][mutation]
+
+    holding N >= 1 of the key's mutations, each with its sequence number, newest first.
+
+[mutation] format:
+
+    [u8 = 0][str] or [u8 = 1]
 
 [str] format:
 
     [unsigned varint][bytes...]
 
-[keys...] format:
+[filter block] format:
 
-    [entry][entry]...[entry][len][u8 length of len]
+    a bloom filter of the table's keys (see bloom.rs), possibly empty
 
-    with the entries in ascending order by key, the last [len] holding the byte length of the last
-    [entry], the last [u8 length of len] holding the byte length of the last [len].
+[index block] format:
 
-[len] format:
+    a block mapping the last key of each data block to the data block's [block handle]
 
-    a varint
+[block handle] format:
 
-[key] format:
+    [unsigned varint offset][unsigned varint size]
 
-    [unsigned varint][unsigned varint][unsigned varint][str]
+    with the size (of the block as stored) not counting the block's checksum.
 
-    with the unsigned varints being the previous entry length, the offset of the value,
-    and length of the value.  The str is the key.
+[footer] format:
+
+    [u64 filter offset][u64 filter size][u64 index offset][u64 index size][u64 largest seq]
+    [u32 codec id][u32 version][magic]
+
+    with the magic being the bytes 'N' 'I' 'H' 't'.  (Version 1 tables, with the values and
+    keys in separate sections, had no version in their footer.  Version 2 tables had no
+    codec id, and no compression.  Version 3 tables had blocks without prefix compression.
+    Version 4 tables had a single mutation per key, and no sequence numbers.)
 */
 
-const TAB_BACK_PADDING: usize = 8;
+const DATA_BLOCK_SIZE: usize = 4096;
+const TABLE_MAGIC: [u8; 4] = ['N' as u8, 'I' as u8, 'H' as u8, 't' as u8];
+const TABLE_VERSION: u32 = 5;
+const FOOTER_SIZE: u64 = 8 + 8 + 8 + 8 + 8 + 4 + 4 + 4;
+
+#[derive(Clone, Copy, Debug)]
+struct BlockHandle {
+    offset: u64,
+    size: u64,
+}
+
+fn encode_handle(v: &mut Vec<u8>, handle: BlockHandle) {
+    encode_uvarint(v, handle.offset);
+    encode_uvarint(v, handle.size);
+}
+
+fn decode_handle(v: &[u8]) -> Result<BlockHandle> {
+    let mut pos: usize = 0;
+    let offset: u64 = decode_uvarint(v, &mut pos).or_err("cannot decode block offset")?;
+    let size: u64 = decode_uvarint(v, &mut pos).or_err("cannot decode block size")?;
+    if pos != v.len() {
+        return mk_err("block handle decoded too small");
+    }
+    return Ok(BlockHandle{offset, size});
+}
+
+// Appends the block and its checksum to buf, returning the block's handle.
+fn append_block(buf: &mut Vec<u8>, block: &[u8]) -> BlockHandle {
+    let handle = BlockHandle{offset: buf.len() as u64, size: block.len() as u64};
+    buf.extend_from_slice(block);
+    encode_u32(buf, crc::crc32::checksum_castagnoli(block));
+    return handle;
+}
+
+struct Footer {
+    filter: BlockHandle,
+    index: BlockHandle,
+    largest_seq: SeqNum,
+    compressor: &'static Compressor,
+}
+
+fn decode_footer(ti: &TableInfo, buf: &[u8]) -> Result<Footer> {
+    if buf.len() as u64 != FOOTER_SIZE {
+        return mk_err("bad table footer size");
+    }
+    if buf[buf.len() - 4..] != TABLE_MAGIC {
+        return mk_err("bad table magic");
+    }
+    let mut pos: usize = 0;
+    let filter_offset: u64 = decode_u64(buf, &mut pos).or_err("cannot decode filter offset")?;
+    let filter_size: u64 = decode_u64(buf, &mut pos).or_err("cannot decode filter size")?;
+    let index_offset: u64 = decode_u64(buf, &mut pos).or_err("cannot decode index offset")?;
+    let index_size: u64 = decode_u64(buf, &mut pos).or_err("cannot decode index size")?;
+    let largest_seq: SeqNum = decode_u64(buf, &mut pos).or_err("cannot decode largest seq")?;
+    let codec_id: u32 = decode_u32(buf, &mut pos).or_err("cannot decode codec id")?;
+    let version: u32 = decode_u32(buf, &mut pos).or_err("cannot decode table version")?;
+    if version != TABLE_VERSION {
+        return Err(Error::VersionError{found: version, expected: TABLE_VERSION});
+    }
+    let footer = Footer{
+        filter: BlockHandle{offset: filter_offset, size: filter_size},
+        index: BlockHandle{offset: index_offset, size: index_size},
+        largest_seq: largest_seq,
+        compressor: compressor_by_id(codec_id).or_err("unknown table codec")?,
+    };
+    let blocks_end: u64 = ti.file_size - FOOTER_SIZE;
+    for handle in [footer.filter, footer.index].iter() {
+        // The handle's fields can be anything, so the block's end mustn't overflow.
+        let end: Option<u64> = handle.offset.checked_add(handle.size).and_then(|x| x.checked_add(4));
+        if end.is_none_or(|end| end > blocks_end) {
+            return mk_err("table footer points past end of file");
+        }
+    }
+    return Ok(footer);
+}
 
-fn encode_mutation(v: &mut Vec<u8>, m: &Mutation) {
+pub fn encode_mutation(v: &mut Vec<u8>, m: &Mutation) {
     match m {
         &Mutation::Set(ref s) => {
             v.push(0);
@@ -64,7 +160,7 @@ fn encode_mutation(v: &mut Vec<u8>, m: &Mutation) {
     }
 }
 
-fn decode_mutation(v: &[u8], pos: &mut usize) -> Option<Mutation> {
+pub fn decode_mutation(v: &[u8], pos: &mut usize) -> Option<Mutation> {
     let b: u8 = *v.get(*pos)?;
     *pos += 1;
     if b == 0 {
@@ -77,23 +173,38 @@ fn decode_mutation(v: &[u8], pos: &mut usize) -> Option<Mutation> {
     }
 }
 
-// NOTE: Could compress key entry lens with key len, remove key len
-
-// NOTE: Should represent mutation with set/delete bit in keys by adding 1 to value len.
+fn encode_versions(v: &mut Vec<u8>, versions: &[(SeqNum, Mutation)]) {
+    encode_uvarint(v, versions.len() as u64);
+    for &(seq, ref m) in versions {
+        encode_uvarint(v, seq);
+        encode_mutation(v, m);
+    }
+}
 
-// NOTE: Could compress value offsets, because values are in order.
+// Decodes a data block's value, which has to be exactly one non-empty [versions].
+fn decode_block_value(v: &[u8]) -> Result<Versions> {
+    let mut pos: usize = 0;
+    let count: u64 = decode_uvarint(v, &mut pos).or_err("cannot decode version count")?;
+    if count == 0 {
+        return mk_err("key with no versions");
+    }
+    let mut versions = Versions::new();
+    for _ in 0..count {
+        let seq: SeqNum = decode_uvarint(v, &mut pos).or_err("cannot decode seq")?;
+        let m: Mutation = decode_mutation(v, &mut pos).or_err("cannot decode mutation")?;
+        versions.push((seq, m));
+    }
+    if pos != v.len() {
+        return mk_err("versions decoded too small");
+    }
+    return Ok(versions);
+}
 
-// NOTE: Compaction could judiciously avoid rewriting value portion of .tab
-// files, by truncating keys off of old .tab files and having keys reference offsets
-// in different tab files.  Compression of value offset would be relative to the
-// neighboring value offset referencing the same .tab file.
+// NOTE: Should represent mutation with set/delete bit in the value length.
 
 // Approximate estimates of disk overhead (within 1% since lengths are varint-encoded).
 pub fn approx_key_usage(key: &[u8]) -> usize {
-    return 1 // prev key entry len
-        + 1 // value len
-        + 3 // value offset
-        + 1 // key len
+    return 1 // key len
         + key.len();
 }
 fn set_value_usage(val: &[u8]) -> usize {
@@ -102,31 +213,47 @@ fn set_value_usage(val: &[u8]) -> usize {
         + val.len();
 }
 pub fn approx_value_usage(val: &Mutation) -> usize {
-    return match val {
-        &Mutation::Set(ref x) => set_value_usage(&x),
-        &Mutation::Delete => 1,
-    };
+    return 1 // mutation len
+        + match val {
+            &Mutation::Set(ref x) => set_value_usage(&x),
+            &Mutation::Delete => 1,
+        };
+}
+pub fn approx_versions_usage(versions: &[(SeqNum, Mutation)]) -> usize {
+    return 1 // version count
+        + versions.iter().map(|&(_, ref m)| 4 // seq, which grows slowly
+                              + approx_value_usage(m)).sum::<usize>();
 }
 
 
 pub struct TableBuilder {
-    values_buf: Vec<u8>,
-    keys_buf: Vec<u8>,
+    // The finished data blocks, with their checksums.
+    buf: Vec<u8>,
+    block: BlockBuilder,
+    index: BlockBuilder,
+    filter: FilterBuilder,
+    compressor: &'static Compressor,
+    // Scratch space for encoding mutations.
+    value_buf: Vec<u8>,
     // NOTE: Instead of copying/allocating these, we could (a) reuse the same
-    // buffer, or (b) decode out of keys_buf when we need the value.
+    // buffer, or (b) decode out of the block when we need the value.
     first_key: Option<Buf>,
     last_key: Option<Buf>,
-    last_entry_len: u64,
+    largest_seq: SeqNum,
 }
 
 impl TableBuilder {
-    pub fn new() -> TableBuilder {
+    pub fn new(bloom_bits_per_key: usize, compression: Compression) -> TableBuilder {
         return TableBuilder{
-            values_buf: Vec::new(),
-            keys_buf: Vec::new(),
+            buf: Vec::new(),
+            block: BlockBuilder::new(),
+            index: BlockBuilder::new(),
+            filter: FilterBuilder::new(bloom_bits_per_key),
+            compressor: compressor(compression),
+            value_buf: Vec::new(),
             first_key: None,
             last_key: None,
-            last_entry_len: 0,
+            largest_seq: 0,
         };
     }
 
@@ -136,328 +263,455 @@ impl TableBuilder {
     }
 
     pub fn lowerbound_file_size(&self) -> usize {
-        return self.values_buf.len() + self.keys_buf.len() + TAB_BACK_PADDING;
+        return self.buf.len() + self.block.size() + self.index.size() + FOOTER_SIZE as usize;
     }
 
-    // This method has to be called in increasing order.
+    // This method has to be called in increasing order, with at least one version.
     // NOTE: Possibly could take key by value.
-    pub fn add_mutation(&mut self, key: &[u8], value: &Mutation) {
+    pub fn add_versions(&mut self, key: &[u8], versions: &[(SeqNum, Mutation)]) {
+        assert!(!versions.is_empty());
         self.last_key = Some(key.to_vec());
         if self.first_key.is_none() {
             self.first_key = self.last_key.clone();
         }
-        let value_offset = self.values_buf.len() as u64;
-        encode_mutation(&mut self.values_buf, value);
-        let value_length = self.values_buf.len() as u64 - value_offset;
-        let pre_pos: usize = self.keys_buf.len();
-        encode_uvarint(&mut self.keys_buf, self.last_entry_len);
-        encode_uvarint(&mut self.keys_buf, value_offset);
-        encode_uvarint(&mut self.keys_buf, value_length);
-        encode_str(&mut self.keys_buf, key);
-        self.last_entry_len = (self.keys_buf.len() - pre_pos) as u64;
-    }
-
-    // Returns keys_offset, file_size, smallest key, biggest key.
-    pub fn finish(mut self, writer: &mut Write) -> Result<(u64, u64, Buf, Buf)> {
+        self.largest_seq = self.largest_seq.max(versions[0].0);
+        self.value_buf.clear();
+        encode_versions(&mut self.value_buf, versions);
+        self.block.add(key, &self.value_buf);
+        self.filter.add_key(key);
+        if self.block.size() >= DATA_BLOCK_SIZE {
+            self.finish_data_block();
+        }
+    }
+
+    fn finish_data_block(&mut self) {
+        let block: Vec<u8> = self.compressor.compress(&self.block.finish());
+        let handle: BlockHandle = append_block(&mut self.buf, &block);
+        let mut handle_buf = Vec::<u8>::new();
+        encode_handle(&mut handle_buf, handle);
+        self.index.add(self.last_key.as_ref().unwrap(), &handle_buf);
+    }
+
+    // Writes and fsyncs the table file, at path.
+    // Returns file_size, smallest key, biggest key, bloom filter.
+    pub fn finish(mut self, f: &mut std::fs::File, path: &str) -> Result<(u64, Buf, Buf, Buf)> {
         assert!(!self.first_key.is_none());
-        let keys_offset = self.values_buf.len() as u64;
-        let pre_offset = self.keys_buf.len();
-        // Encode last value of pre_pos.
-        encode_uvarint(&mut self.keys_buf, self.last_entry_len);
-        // Encode length of last uvarint, so we can step backwards.
-        let step_back = (self.keys_buf.len() - pre_offset) as u8;
-        self.keys_buf.push(step_back);
-        encode_u64(&mut self.keys_buf, keys_offset);  // NOTE: Not necessary now that it's in TOC.
-        writer.write_all(&self.values_buf)?;
-        writer.write_all(&self.keys_buf)?;
-        writer.flush()?;
+        if !self.block.is_empty() {
+            self.finish_data_block();
+        }
+        let filter: Buf = self.filter.finish();
+        let filter_handle: BlockHandle = append_block(&mut self.buf, &filter);
+        let index: Vec<u8> = self.index.finish();
+        let index_handle: BlockHandle = append_block(&mut self.buf, &index);
+
+        encode_u64(&mut self.buf, filter_handle.offset);
+        encode_u64(&mut self.buf, filter_handle.size);
+        encode_u64(&mut self.buf, index_handle.offset);
+        encode_u64(&mut self.buf, index_handle.size);
+        encode_u64(&mut self.buf, self.largest_seq);
+        encode_u32(&mut self.buf, self.compressor.id());
+        encode_u32(&mut self.buf, TABLE_VERSION);
+        self.buf.extend_from_slice(&TABLE_MAGIC);
+
+        f.write_all(&self.buf).at_path(path)?;
+        f.sync_data().at_path(path)?;
         return Ok((
-            keys_offset,
-            keys_offset + self.keys_buf.len() as u64,
-            self.first_key.as_ref().unwrap().clone(),
-            self.last_key.as_ref().unwrap().clone(),
+            self.buf.len() as u64,
+            self.first_key.unwrap(),
+            self.last_key.unwrap(),
+            filter,
         ));
     }
 }
 
-// Returns keys_offset, file_size, smallest key, biggest key.
-pub fn flush_to_disk<'a>(dir: &str, table_id: TableId, m: &'a MemStore) -> Result<(u64, u64, Buf, Buf)> {
+// Writes the memstore's versions that the live `snapshots` (in ascending order) might read.
+// Returns file_size, smallest key, biggest key, bloom filter.
+pub fn flush_to_disk<'a>(dir: &str, table_id: TableId, m: &'a MemStore, snapshots: &[SeqNum],
+                         bloom_bits_per_key: usize, compression: Compression) -> Result<(u64, Buf, Buf, Buf)> {
     assert!(!m.entries.is_empty());
-    let mut builder = TableBuilder::new();
-    
-    for (key, value) in m.entries.iter() {
-        builder.add_mutation(key, value);
+    let mut builder = TableBuilder::new(bloom_bits_per_key, compression);
+
+    for (key, versions) in m.entries.iter() {
+        let mut versions: Versions = versions.clone();
+        retain_readable(&mut versions, snapshots);
+        builder.add_versions(key, &versions);
     }
-    let mut f = std::fs::File::create(table_filepath(dir, table_id))?;
-    return builder.finish(&mut f);
+    return write_table(dir, table_id, builder);
+}
+
+// Writes the table to a temp file and renames it into place, so that a crash never
+// leaves a partially written .tab file.  The caller still has to sync the directory.
+// Returns file_size, smallest key, biggest key, bloom filter.
+pub fn write_table(dir: &str, table_id: TableId, builder: TableBuilder) -> Result<(u64, Buf, Buf, Buf)> {
+    let tmp_path: String = table_tmp_filepath(dir, table_id);
+    let mut f = std::fs::File::create(&tmp_path).at_path(&tmp_path)?;
+    let ret = builder.finish(&mut f, &tmp_path)?;
+    std::fs::rename(&tmp_path, table_filepath(dir, table_id)).at_path(&tmp_path)?;
+    return Ok(ret);
 }
 
-fn open_table_file(dir: &str, table_id: TableId) -> Result<std::fs::File> {
-    let f = std::fs::File::open(table_filepath(dir, table_id))?;
-    return Ok(f);
+// Makes creations and removals of files in the directory durable.
+pub fn sync_directory(dir: &str) -> Result<()> {
+    let f = std::fs::File::open(dir).at_path(dir)?;
+    f.sync_all().at_path(dir)?;
+    return Ok(());
+}
+
+// An open table file, and its path for error messages.
+struct TableFile {
+    f: std::fs::File,
+    path: String,
+}
+
+fn open_table_file(dir: &str, table_id: TableId) -> Result<TableFile> {
+    let path: String = table_filepath(dir, table_id);
+    let f = std::fs::File::open(&path).at_path(&path)?;
+    return Ok(TableFile{f: f, path: path});
 }
 
-// NOTE: We'll want to use pread.
-fn read_exact(f: &mut std::fs::File, offset: u64, length: usize) -> Result<Vec<u8>> {
+fn read_exact(tf: &TableFile, offset: u64, length: usize) -> Result<Vec<u8>> {
     // NOTE: Can we use unsafe to get uninitialized buf
-    f.seek(std::io::SeekFrom::Start(offset))?;
     let mut buf = Vec::<u8>::new();
     buf.resize(length, 0u8);
-    f.read_exact(&mut buf)?;
+    // A positional read needs no &mut, so every user of a cached table can share its file.
+    tf.f.read_exact_at(&mut buf, offset).at_path(&tf.path)?;
     return Ok(buf);
 }
 
-pub fn lookup_table(dir: &str, ti: &TableInfo, key: &[u8]) -> Result<Option<Mutation>> {
-    let (mut f, keys_buf) = load_table_keys_buf(dir, ti)?;
-    
-    // NOTE: Give file better random access structure
-    let mut iter = TableKeysIterator::whole_table(RcRef::new(Rc::new(keys_buf)).map(|v: &Vec<u8>| v as &[u8]))?;
-    while let Some((iter_key, value_offset, value_length)) = iter.next_key()? {
-        match key.cmp(iter_key) {
-            Ordering::Less => {
-                break;
-            },
-            Ordering::Equal => {
-                let value_length = try_into_size(value_length).or_err("value length too big")?;
-                let value_buf: Vec<u8> = read_exact(&mut f, value_offset, value_length)?;
-                let mut pos: usize = 0;
-                let value: Mutation = decode_mutation(&value_buf, &mut pos).or_err("cannot decode mutation")?;
-                if pos != value_buf.len() {
-                    return mk_err("mutation decoded too small");
-                }
-                return Ok(Some(value));
-            },
-            Ordering::Greater => (),
-        };
+fn check_checksum(table_id: TableId, offset: u64, buf: &[u8], checksum: u32) -> Result<()> {
+    if crc::crc32::checksum_castagnoli(buf) != checksum {
+        return Err(Error::CorruptionError{table_id: table_id.0, offset: offset});
     }
-
-    return Ok(None);
+    return Ok(());
 }
 
-struct TableKeysIterator {
-    keys: RcRef<Vec<u8>, [u8]>,
-    // Position after the last entry, but before the last entry length or its 1-byte length
-    keys_pos: usize,
-    keys_end_pos: usize,
+// Checks the checksum that follows the block in buf, which holds the block and its checksum.
+fn check_block(table_id: TableId, handle: BlockHandle, buf: &[u8]) -> Result<()> {
+    let size: usize = buf.len() - 4;
+    let mut pos: usize = size;
+    let checksum: u32 = decode_u32(buf, &mut pos).or_err("cannot decode block checksum")?;
+    return check_checksum(table_id, handle.offset, &buf[..size], checksum);
 }
 
-struct TableKeysInterval {
-    keys_pos: usize,
-    keys_end_pos: usize,
+// Reads the block's contents, verifying its checksum.
+fn read_block(f: &TableFile, table_id: TableId, handle: BlockHandle) -> Result<Vec<u8>> {
+    let size: usize = try_into_size(handle.size).or_err("block too big")?;
+    let mut buf: Vec<u8> = read_exact(f, handle.offset, size.checked_add(4).or_err("block too big")?)?;
+    check_block(table_id, handle, &buf)?;
+    buf.truncate(size);
+    return Ok(buf);
 }
 
-impl TableKeysIterator {
-    fn whole_table(keys: RcRef<Vec<u8>, [u8]>) -> Result<TableKeysIterator> {
-        let step_back = *keys.get(keys.len() - 1).or_err("table keys buffer too small")? as usize;
-        if keys.len() < 1 + step_back {
-            return mk_err("table keys step_back too small");
-        }
-        let keys_end_pos: usize = keys.len() - 1 - step_back;
-        return Ok(TableKeysIterator{keys: keys, keys_pos: 0, keys_end_pos: keys_end_pos});
-    }
+// Like read_block, but borrowing the block's contents from the mapped file.
+fn map_block_contents(map: &Mmap, table_id: TableId, handle: BlockHandle) -> Result<&[u8]> {
+    let offset: usize = try_into_size(handle.offset).or_err("block offset too big")?;
+    let size: usize = try_into_size(handle.size).or_err("block too big")?;
+    let end: usize = offset.checked_add(size).and_then(|x| x.checked_add(4)).or_err("block past end of file")?;
+    let buf: &[u8] = map.as_ref().get(offset..end).or_err("block past end of file")?;
+    check_block(table_id, handle, buf)?;
+    return Ok(&buf[..size]);
+}
 
-    fn save_pos(&self) -> TableKeysInterval {
-        return TableKeysInterval{keys_pos: self.keys_pos, keys_end_pos: self.keys_end_pos};
-    }
+fn map_block(map: &Arc<Mmap>, table_id: TableId, handle: BlockHandle) -> Result<Block> {
+    let size: usize = map_block_contents(map, table_id, handle)?.len();
+    return Block::in_storage(map.clone(), handle.offset as usize, size);
+}
 
-    fn decode_key<'a>(keys: &'a RcRef<Vec<u8>, [u8]>, pos: &mut usize) -> Result<(&'a [u8], u64, u64)> {
-        let _prev_entry_length: u64 = decode_uvarint(keys, pos)
-            .or_err("could not decode prev entry length")?;
-        let value_offset: u64 = decode_uvarint(keys, pos)
-            .or_err("could not decode value_offset")?;
-        let value_length: u64 = decode_uvarint(keys, pos)
-            .or_err("could not decode value_length")?;
-        let key: &[u8] = observe_str(&*keys, pos).or_err("cannot decode key")?;
-        return Ok((key, value_offset, value_length));
+fn read_footer(f: &TableFile, ti: &TableInfo) -> Result<Footer> {
+    if ti.file_size < FOOTER_SIZE {
+        return mk_err("table file too small");
     }
+    let footer_buf: Vec<u8> = read_exact(f, ti.file_size - FOOTER_SIZE, FOOTER_SIZE as usize)?;
+    return decode_footer(ti, &footer_buf);
+}
 
-    fn help_current_key(keys: &RcRef<Vec<u8>, [u8]>, keys_pos: usize, keys_end_pos: usize) -> Result<Option<(&[u8], u64, u64)>> {
-        if keys_pos == keys_end_pos {
-            return Ok(None);
-        }
-        // NOTE: It might be nice if this came pre-decoded.
-        let mut pos = keys_pos;
-        let tup = TableKeysIterator::decode_key(keys, &mut pos)?;
-        return Ok(Some(tup));
-    }
+// Returns the table's bloom filter, and the largest sequence number of its mutations.
+pub fn load_filter(dir: &str, ti: &TableInfo) -> Result<(Buf, SeqNum)> {
+    let f: TableFile = open_table_file(dir, ti.id)?;
+    let footer: Footer = read_footer(&f, ti)?;
+    return Ok((read_block(&f, ti.id, footer.filter)?, footer.largest_seq));
+}
 
-    fn current_key(&self) -> Result<Option<(&[u8], u64, u64)>> {
-        return TableKeysIterator::help_current_key(&self.keys, self.keys_pos, self.keys_end_pos);
-    }
+// An open table file, with its footer and index block read.
+pub struct OpenTable {
+    table_id: TableId,
+    f: TableFile,
+    // The whole file, if we're reading it through a memory map.
+    map: Option<Arc<Mmap>>,
+    index: Block,
+    // The codec of the data blocks.
+    compressor: &'static Compressor,
+}
 
-    // Helper separates the mutability of kesy from keys_pos for use with
-    // help_current_key.
-    fn help_step_key(keys: &RcRef<Vec<u8>, [u8]>, keys_pos: &mut usize) -> Result<()> {
-        let mut pos = *keys_pos;
-        let _ = TableKeysIterator::decode_key(&keys, &mut pos)?;
-        *keys_pos = pos;
-        return Ok(());
-    }
+fn open_table(dir: &str, ti: &TableInfo, mmap: bool) -> Result<OpenTable> {
+    let f: TableFile = open_table_file(dir, ti.id)?;
+    let footer: Footer = read_footer(&f, ti)?;
+    let map: Option<Arc<Mmap>> = if mmap {
+        Some(Arc::new(Mmap::map(&f.f, &f.path, try_into_size(ti.file_size).or_err("table file too big")?)?))
+    } else {
+        None
+    };
+    let index: Block = match map {
+        Some(ref map) => map_block(map, ti.id, footer.index)?,
+        None => Block::new(read_block(&f, ti.id, footer.index)?)?,
+    };
+    return Ok(OpenTable{table_id: ti.id, f: f, map: map, index: index, compressor: footer.compressor});
+}
 
-    fn step_key(&mut self) -> Result<()> {
-        return TableKeysIterator::help_step_key(&self.keys, &mut self.keys_pos)
+// Gets the open table from the table cache, or opens it and puts it there.
+fn get_table(dir: &str, ti: &TableInfo, tables: &SharedTableCache) -> Result<Arc<OpenTable>> {
+    if let Some(table) = tables.lock().unwrap().get(ti.id) {
+        return Ok(table);
     }
+    // Open the file without holding the lock, so that other readers aren't kept waiting.
+    let mmap: bool = tables.lock().unwrap().mmap();
+    let table = Arc::new(open_table(dir, ti, mmap)?);
+    tables.lock().unwrap().insert(ti.id, table.clone());
+    return Ok(table);
+}
 
-    fn next_key(&mut self) -> Result<Option<(&[u8], u64, u64)>> {
-        if let Some(ret) = TableKeysIterator::help_current_key(&self.keys, self.keys_pos, self.keys_end_pos)? {
-            TableKeysIterator::help_step_key(&self.keys, &mut self.keys_pos)?;
-            return Ok(Some(ret));
+impl OpenTable {
+    // Gets the data block from the mapped file, or from the cache, or reads and decompresses it
+    // and puts it in the cache.  (Uncompressed mapped blocks skip the cache, since they take
+    // no memory of their own.)
+    fn read_cached_block(&self, handle: BlockHandle, cache: &SharedBlockCache) -> Result<Block> {
+        let compressed: bool = self.compressor.id() != NoCompressor.id();
+        if let (&Some(ref map), false) = (&self.map, compressed) {
+            return map_block(map, self.table_id, handle);
         }
-        return Ok(None);
+        if let Some(block) = cache.lock().unwrap().get(self.table_id, handle.offset) {
+            return Ok(block);
+        }
+        let contents: Vec<u8> = match self.map {
+            Some(ref map) => self.compressor.decompress(map_block_contents(map, self.table_id, handle)?)?,
+            None if compressed => self.compressor.decompress(&read_block(&self.f, self.table_id, handle)?)?,
+            None => read_block(&self.f, self.table_id, handle)?,
+        };
+        let block = Block::new(contents)?;
+        cache.lock().unwrap().insert(self.table_id, handle.offset, block.clone());
+        return Ok(block);
     }
 
-    fn current_back_key(&self) -> Result<Option<(&[u8], u64, u64)>> {
-        if self.keys_pos == self.keys_end_pos {
-            return Ok(None);
-        }
-        let mut pos = self.keys_end_pos;
-        let entry_length = decode_uvarint(&self.keys, &mut pos)
-            .or_err("cannot decode prev length")?;
-        assert!(entry_length > 0);  // NOTE: error handling
-        // NOTE: usize conversion
-        let ret = TableKeysIterator::help_current_key(
-            &self.keys, self.keys_end_pos - entry_length as usize, self.keys_end_pos);
-        return ret;
-    }
-
-    // This works like a DoubleEndedIterator -- steps backwards.
-    fn step_back_key(&mut self) -> Result<bool> {
-        if self.keys_pos == self.keys_end_pos {
-            return Ok(false);
+    // Reads the data block that the index iterator's current entry points at.
+    fn read_data_block(&self, index_iter: &BlockIter, cache: &SharedBlockCache) -> Result<Option<BlockIter>> {
+        if let Some(handle_buf) = index_iter.value() {
+            let handle: BlockHandle = decode_handle(handle_buf)?;
+            let block: Block = self.read_cached_block(handle, cache)?;
+            return Ok(Some(BlockIter::new(block)));
         }
-        let mut pos = self.keys_end_pos;
-        let entry_length = decode_uvarint(&self.keys, &mut pos).or_err("cannot decode prev length")?;
-        assert!(entry_length > 0);  // NOTE: Handle error better
-        self.keys_end_pos -= entry_length as usize;  // NOTE: Handle conversion
-        assert!(self.keys_pos <= self.keys_end_pos);
-        return Ok(true);
-    }
-}
-
-pub fn load_table_keys_buf(dir: &str, ti: &TableInfo) -> Result<(std::fs::File, Vec<u8>)> {
-    let mut f: std::fs::File = open_table_file(dir, ti.id)?;
-    // NOTE: Make these guarantees of TableInfo.
-    let keys_offset: usize = try_into_size(ti.keys_offset).or_err("lookup_table keys_offset")?;
-    let file_size: usize = try_into_size(ti.file_size).or_err("lookup_table file_size")?;
-    assert!(file_size >= TAB_BACK_PADDING && file_size - TAB_BACK_PADDING >= keys_offset);
-    let keys_buf = read_exact(&mut f, ti.keys_offset, file_size - TAB_BACK_PADDING - keys_offset)?;
-    return Ok((f, keys_buf));
-}
-
-fn advance_past_lower_bound(iter: &mut TableKeysIterator, lower: &Bound<Buf>) -> Result<()> {
-    // NOTE: Double-decodes keys.
-    while let Some((key, _, _)) = TableKeysIterator::help_current_key(&iter.keys, iter.keys_pos, iter.keys_end_pos)? {
-        if above_lower_bound(key, lower) {
-            return Ok(());
-        }
-        TableKeysIterator::help_step_key(&iter.keys, &mut iter.keys_pos)?;
+        return Ok(None);
     }
-    return Ok(());
 }
 
-fn advance_before_upper_bound(iter: &mut TableKeysIterator, upper: &Bound<Buf>) -> Result<()> {
-    loop {
-        let pos = iter.save_pos();
-        if !iter.step_back_key()? {
-            return Ok(());
-        }
-        let (key, _, _) = TableKeysIterator::help_current_key(&iter.keys, iter.keys_end_pos, pos.keys_end_pos)?
-            .or_err("current_key after step_back_key")?;
-        if below_upper_bound(key, upper) {
-            iter.keys_pos = pos.keys_pos;
-            iter.keys_end_pos = pos.keys_end_pos;
-            return Ok(());
+// Returns the newest version of the key at or before the snapshot.
+pub fn lookup_table(dir: &str, ti: &TableInfo, key: &[u8], snapshot: SeqNum, tables: &SharedTableCache,
+                    cache: &SharedBlockCache) -> Result<Option<(SeqNum, Mutation)>> {
+    let ot: Arc<OpenTable> = get_table(dir, ti, tables)?;
+
+    // The only block that could hold the key is the first one whose last key is >= key.
+    let mut index_iter = BlockIter::new(ot.index.clone());
+    index_iter.seek(key)?;
+    if let Some(mut iter) = ot.read_data_block(&index_iter, cache)? {
+        iter.seek(key)?;
+        if iter.key() == Some(key) {
+            let versions: Versions = decode_block_value(iter.value().unwrap())?;
+            return Ok(versions.into_iter().find(|&(seq, _)| seq <= snapshot));
         }
     }
+    return Ok(None);
 }
 
 pub struct TableIterator {
-    keys_iter: TableKeysIterator,
-    // values_buf is just a slice of the table file that we're going to iterate,
-    // pre-computed based on key range.  So any offsets into it need to have
-    // offset_of_values_buf subtracted.
-    values_buf: Vec<u8>,
-    offset_of_values_buf: u64,
+    table: Arc<OpenTable>,
+    cache: SharedBlockCache,
+    index_iter: BlockIter,
+    // The current data block, or None if we've run off the end of the index.
+    data_iter: Option<BlockIter>,
+    interval: Interval<Buf>,
     direction: Direction,
+    // Keys with no version at or before this get skipped.
+    snapshot: SeqNum,
 }
 
 impl TableIterator {
-    pub fn make(dir: &str, ti: &TableInfo, interval: &Interval<Buf>, direction: Direction
+    pub fn make(dir: &str, ti: &TableInfo, interval: &Interval<Buf>, direction: Direction, snapshot: SeqNum,
+                tables: &SharedTableCache, cache: &SharedBlockCache
     ) -> Result<TableIterator> {
-        let (mut f, keys_buf) = load_table_keys_buf(dir, ti)?;
-        let mut keys_iter = TableKeysIterator::whole_table(RcRef::new(Rc::new(keys_buf)).map(|v| v as &[u8]))?;
-        advance_past_lower_bound(&mut keys_iter, &interval.lower)?;
-        advance_before_upper_bound(&mut keys_iter, &interval.upper)?;
-        // NOTE: We could use the upper bound to read fewer values.
-        if let Some((_, value_offset, _)) = TableIterator::help_current_entry(&keys_iter, Direction::Forward)? {
-            let length: usize = try_into_size(ti.keys_offset - value_offset).or_err("bad value_offset")?;
-            let values_buf: Vec<u8> = read_exact(&mut f, value_offset, length)?;
-            return Ok(TableIterator{
-                keys_iter: keys_iter,
-                values_buf: values_buf,
-                offset_of_values_buf: value_offset,
-                direction: direction,
-            });
-        } else {
-            return Ok(TableIterator{
-                keys_iter: keys_iter,
-                // keys_iter is empty, so these will never get used.
-                values_buf: Vec::<u8>::new(),
-                offset_of_values_buf: 0,
-                direction: direction,
-            });
+        let table: Arc<OpenTable> = get_table(dir, ti, tables)?;
+        let index_iter = BlockIter::new(table.index.clone());
+        let mut ret = TableIterator{
+            table: table,
+            cache: cache.clone(),
+            index_iter: index_iter,
+            data_iter: None,
+            interval: interval.clone(),
+            direction: direction,
+            snapshot: snapshot,
+        };
+        match direction {
+            Direction::Forward => ret.seek_forward()?,
+            Direction::Backward => ret.seek_backward()?,
+        }
+        ret.skip_invisible()?;
+        return Ok(ret);
+    }
+
+    fn load_data_block(&mut self) -> Result<()> {
+        self.data_iter = self.table.read_data_block(&self.index_iter, &self.cache)?;
+        return Ok(());
+    }
+
+    // The current key, ignoring the interval.
+    fn raw_key(&self) -> Option<&[u8]> {
+        return self.data_iter.as_ref().and_then(|d| d.key());
+    }
+
+    // Positions the iterator at the first key above the lower bound.
+    fn seek_forward(&mut self) -> Result<()> {
+        let target: Option<Buf> = match &self.interval.lower {
+            &Bound::Included(ref k) | &Bound::Excluded(ref k) => Some(k.clone()),
+            &Bound::Unbounded => None,
+        };
+        match &target {
+            &Some(ref k) => self.index_iter.seek(k)?,
+            &None => self.index_iter.seek_to_first()?,
+        }
+        self.load_data_block()?;
+        if let Some(ref mut d) = self.data_iter {
+            match &target {
+                &Some(ref k) => d.seek(k)?,
+                &None => d.seek_to_first()?,
+            }
+        }
+        self.skip_forward()?;
+        // Only an Excluded bound can leave us on a key that's not above it.
+        while let Some(key) = self.raw_key() {
+            if above_lower_bound(key, &self.interval.lower) {
+                break;
+            }
+            self.raw_step()?;
         }
+        return Ok(());
     }
 
-    fn help_current_entry(keys_iter: &TableKeysIterator, direction: Direction
-    ) -> Result<Option<(&[u8], u64, u64)>> {
-        return match direction {
-            Direction::Forward => keys_iter.current_key(),
-            Direction::Backward => keys_iter.current_back_key()
+    // Positions the iterator at the last key below the upper bound.
+    fn seek_backward(&mut self) -> Result<()> {
+        let target: Option<Buf> = match &self.interval.upper {
+            &Bound::Included(ref k) | &Bound::Excluded(ref k) => Some(k.clone()),
+            &Bound::Unbounded => None,
         };
+        if let Some(ref k) = target {
+            self.index_iter.seek(k)?;
+        }
+        match target {
+            Some(ref k) if self.index_iter.valid() => {
+                self.load_data_block()?;
+                if let Some(ref mut d) = self.data_iter {
+                    d.seek(k)?;
+                }
+            }
+            _ => {
+                // Every key is below the bound.
+                self.index_iter.seek_to_last()?;
+                self.load_data_block()?;
+                if let Some(ref mut d) = self.data_iter {
+                    d.seek_to_last()?;
+                }
+            }
+        }
+        self.skip_backward()?;
+        while let Some(key) = self.raw_key() {
+            if below_upper_bound(key, &self.interval.upper) {
+                break;
+            }
+            self.raw_step()?;
+        }
+        return Ok(());
     }
 
-    fn current_entry(&self) -> Result<Option<(&[u8], u64, u64)>> {
-        return TableIterator::help_current_entry(&self.keys_iter, self.direction);
+    // Moves on to the next data block, if we've stepped off the end of the current one.
+    fn skip_forward(&mut self) -> Result<()> {
+        while self.data_iter.as_ref().is_some_and(|d| !d.valid()) {
+            self.index_iter.next()?;
+            self.load_data_block()?;
+            if let Some(ref mut d) = self.data_iter {
+                d.seek_to_first()?;
+            }
+        }
+        return Ok(());
     }
-}
 
-impl MutationIterator for TableIterator {
-    fn current_key(&self) -> Result<Option<&[u8]>> {
-        let ret = self.current_entry().map(|x| x.map(|(k, _, _)| k));
-        return ret;
+    // Moves back to the previous data block, if we've stepped off the front of the current one.
+    fn skip_backward(&mut self) -> Result<()> {
+        while self.data_iter.as_ref().is_some_and(|d| !d.valid()) {
+            self.index_iter.prev()?;
+            self.load_data_block()?;
+            if let Some(ref mut d) = self.data_iter {
+                d.seek_to_last()?;
+            }
+        }
+        return Ok(());
     }
 
-    fn current_value(&mut self) -> Result<Mutation> {
-        if let Some((_, value_offset, value_length)) = self.current_entry()? {
-            let value_rel_offset: u64 = value_offset - self.offset_of_values_buf;
-            let value_rel_offset = try_into_size(value_rel_offset).or_err("value_rel_offset not size")?;
-            let value_length = try_into_size(value_length).or_err("value_length not size")?;
-
-            let sl: &[u8] = self.values_buf.get(value_rel_offset..value_rel_offset + value_length)
-                .or_err("bad value offset/length")?;
-
-            let mut pos: usize = 0;
-            let value: Mutation = decode_mutation(sl, &mut pos).or_err("cannot decode mutation")?;
-            if pos != value_length {
-                return mk_err("mutation decoded too small");
+    // Steps past keys whose versions are all newer than the snapshot.
+    fn skip_invisible(&mut self) -> Result<()> {
+        while self.current_key()?.is_some() {
+            let versions: Versions = decode_block_value(self.data_iter.as_ref().unwrap().value().unwrap())?;
+            if visible_version(&versions, self.snapshot).is_some() {
+                break;
             }
-            return Ok(value);
+            self.raw_step()?;
         }
-        return mk_err("current_value called on empty TableIterator");
+        return Ok(());
     }
 
-    fn step(&mut self) -> Result<()> {
+    fn raw_step(&mut self) -> Result<()> {
         match self.direction {
             Direction::Forward => {
-                return self.keys_iter.step_key();
+                self.data_iter.as_mut().or_invalid_arg("cannot step forward in TableIterator")?.next()?;
+                return self.skip_forward();
             },
             Direction::Backward => {
-                if !self.keys_iter.step_back_key()? {
-                    return mk_err("cannot step backward in TableIterator");
-                }
-                return Ok(());
+                self.data_iter.as_mut().or_invalid_arg("cannot step backward in TableIterator")?.prev()?;
+                return self.skip_backward();
             }
         }
     }
 }
+
+impl MutationIterator for TableIterator {
+    fn current_key(&self) -> Result<Option<&[u8]>> {
+        if let Some(key) = self.raw_key() {
+            let in_bounds: bool = match self.direction {
+                Direction::Forward => below_upper_bound(key, &self.interval.upper),
+                Direction::Backward => above_lower_bound(key, &self.interval.lower),
+            };
+            if in_bounds {
+                return Ok(Some(key));
+            }
+        }
+        return Ok(None);
+    }
+
+    fn current_value(&mut self) -> Result<Mutation> {
+        let mut versions: Versions = self.current_versions()?;
+        return Ok(versions.swap_remove(0).1);
+    }
+
+    fn current_versions(&mut self) -> Result<Versions> {
+        if self.current_key()?.is_none() {
+            return invalid_arg_err("current_versions called on empty TableIterator");
+        }
+        let mut versions: Versions = decode_block_value(self.data_iter.as_ref().unwrap().value().unwrap())?;
+        versions.retain(|&(seq, _)| seq <= self.snapshot);
+        return Ok(versions);
+    }
+
+    fn step(&mut self) -> Result<()> {
+        if self.current_key()?.is_none() {
+            return invalid_arg_err("step called on empty TableIterator");
+        }
+        self.raw_step()?;
+        return self.skip_invisible();
+    }
+}
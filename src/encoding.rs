@@ -1,5 +1,7 @@
 use util::*;
 
+use crc;
+
 // Format for varints:  Base-128, little-endian, [1][7bit] ... [1][7bit] [0][7bit]
 
 pub fn encode_uvarint(v: &mut Vec<u8>, mut n: u64) {
@@ -116,6 +118,40 @@ pub fn decode_str(v: &[u8], pos: &mut usize) -> Option<Buf> {
     return Some(s.to_vec());
 }
 
+// Format for checksummed records:  [u64 length][u32 crc32c of payload][payload]
+
+pub fn encode_checksummed(v: &mut Vec<u8>, payload: &[u8]) {
+    encode_u64(v, payload.len() as u64);
+    encode_u32(v, crc::crc32::checksum_castagnoli(payload));
+    v.extend_from_slice(payload);
+}
+
+// Returns None if the record is truncated or its checksum doesn't match.
+pub fn observe_checksummed<'a>(v: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    let mut p: usize = *pos;
+    let length: usize = try_into_size(decode_u64(v, &mut p)?)?;
+    let checksum: u32 = decode_u32(v, &mut p)?;
+    if length > v.len() - p {
+        return None;
+    }
+    let payload: &[u8] = &v[p..p + length];
+    if checksum != crc::crc32::checksum_castagnoli(payload) {
+        return None;
+    }
+    *pos = p + length;
+    return Some(payload);
+}
+
+// Whether the record at pos runs past the end of v -- which is what a record whose append
+// got cut short looks like, as opposed to one that got damaged.
+pub fn checksummed_runs_past_end(v: &[u8], pos: usize) -> bool {
+    let mut p: usize = pos;
+    return match (decode_u64(v, &mut p), decode_u32(v, &mut p)) {
+        (Some(length), Some(_)) => length > (v.len() - p) as u64,
+        _ => true,
+    };
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -153,6 +189,23 @@ mod tests {
         }
     }
 
+    #[test]
+    fn checksummed() {
+        let mut v = Vec::<u8>::new();
+        super::encode_checksummed(&mut v, "payload".as_bytes());
+        let mut pos: usize = 0;
+        assert_eq!(Some("payload".as_bytes()), super::observe_checksummed(&v, &mut pos));
+        assert_eq!(v.len(), pos);
+
+        let last = v.len() - 1;
+        v[last] ^= 1;
+        pos = 0;
+        assert_eq!(None, super::observe_checksummed(&v, &mut pos));
+        assert_eq!(0, pos);
+        pos = 0;
+        assert_eq!(None, super::observe_checksummed(&v[..last], &mut pos));
+    }
+
     #[test]
     fn uint() {
         help_test_enc64(0);
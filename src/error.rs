@@ -2,29 +2,86 @@ use std;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// For data that doesn't decode the way it should.
 pub fn mk_err<T>(msg: &str) -> Result<T> { Err(Error::new(msg)) }
 
+// For callers (including our own iterator code) using the API wrong.
+pub fn invalid_arg_err<T>(msg: &str) -> Result<T> { Err(Error::InvalidArgumentError(msg.to_string())) }
+
+/// What kind of error an `Error` is.  These are stable, so that callers can decide
+/// what to do (retry, alert, give up) without looking at messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// An I/O error, other than a missing file.  Possibly transient.
+    Io,
+    /// A file (or the store directory) doesn't exist.
+    NotFound,
+    /// Data on disk is damaged.
+    Corruption,
+    /// A file on disk has a format version we don't understand.
+    VersionMismatch,
+    /// Another `Store` has the directory open.
+    Locked,
+    /// The API was used wrongly.
+    InvalidArgument,
+    /// A transaction read a key that another write changed before it committed.  Retrying
+    /// the transaction might succeed.
+    Conflict,
+}
+
 #[derive(Debug)]
 pub enum Error {
-    IoError(std::io::Error),
-    MsgError(String),
+    // Holds the path of the file involved, if we know it.
+    IoError(std::io::Error, Option<String>),
+    // Data on disk that doesn't decode.
+    MalformedError(String),
+    // A checksum didn't match, in the given table file at the given offset.
+    CorruptionError{table_id: u64, offset: u64},
+    // The toc or a table file has a format version other than the one we know.
+    VersionError{found: u32, expected: u32},
+    // Another Store has the directory open.  Holds the path of the lock file.
+    LockedError(String),
+    InvalidArgumentError(String),
+    // A transaction read a key that got written after it began.  Holds the key.
+    ConflictError(Vec<u8>),
 }
 
 impl Error {
     pub fn new(msg: &str) -> Error {
-        return Error::MsgError(msg.to_string());
+        return Error::MalformedError(msg.to_string());
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        return match self {
+            &Error::IoError(ref e, _) => match e.kind() {
+                std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+                _ => ErrorKind::Io,
+            },
+            &Error::MalformedError(_) => ErrorKind::Corruption,
+            &Error::CorruptionError{..} => ErrorKind::Corruption,
+            &Error::VersionError{..} => ErrorKind::VersionMismatch,
+            &Error::LockedError(_) => ErrorKind::Locked,
+            &Error::InvalidArgumentError(_) => ErrorKind::InvalidArgument,
+            &Error::ConflictError(_) => ErrorKind::Conflict,
+        };
     }
 
     pub fn msg(&self) -> &str {
         return match self {
-            &Error::IoError(ref e) => (e as &std::error::Error).description(),
-            &Error::MsgError(ref s) => &s,
+            &Error::IoError(ref e, _) => (e as &std::error::Error).description(),
+            &Error::MalformedError(ref s) => &s,
+            &Error::CorruptionError{..} => "table checksum mismatch",
+            &Error::VersionError{..} => "unsupported format version",
+            &Error::LockedError(_) => "store is locked by another process",
+            &Error::InvalidArgumentError(ref s) => &s,
+            &Error::ConflictError(_) => "transaction conflicts with a later write",
         };
     }
 }
 
 pub trait OptionExt<T> {
     fn or_err(self, msg: &str) -> std::result::Result<T, Error>;
+    fn or_invalid_arg(self, msg: &str) -> std::result::Result<T, Error>;
 }
 
 impl<T> OptionExt<T> for Option<T> {
@@ -34,11 +91,48 @@ impl<T> OptionExt<T> for Option<T> {
         }
         return Err(Error::new(msg));
     }
+
+    fn or_invalid_arg(self, msg: &str) -> std::result::Result<T, Error> {
+        if let Some(x) = self {
+            return Ok(x);
+        }
+        return Err(Error::InvalidArgumentError(msg.to_string()));
+    }
+}
+
+pub trait IoResultExt<T> {
+    // Attaches the path to an I/O error.
+    fn at_path(self, path: &str) -> std::result::Result<T, Error>;
+}
+
+impl<T> IoResultExt<T> for std::io::Result<T> {
+    fn at_path(self, path: &str) -> std::result::Result<T, Error> {
+        return self.map_err(|e| Error::IoError(e, Some(path.to_string())));
+    }
+}
+
+// For best-effort cleanup:  keeps the first error, ignoring files that are already gone.
+pub fn note_cleanup_error(first: &mut Option<Error>, result: std::result::Result<(), Error>) {
+    if let Err(e) = result {
+        if first.is_none() && e.kind() != ErrorKind::NotFound {
+            *first = Some(e);
+        }
+    }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        return write!(f, "{}", self.msg());
+        return match self {
+            &Error::IoError(ref e, Some(ref path)) => write!(f, "{}: {}", path, e),
+            &Error::IoError(ref e, None) => write!(f, "{}", e),
+            &Error::CorruptionError{table_id, offset} =>
+                write!(f, "{} (table {}, offset {})", self.msg(), table_id, offset),
+            &Error::VersionError{found, expected} =>
+                write!(f, "{} (found {}, expected {})", self.msg(), found, expected),
+            &Error::LockedError(ref path) => write!(f, "{} ({})", self.msg(), path),
+            &Error::ConflictError(ref key) => write!(f, "{} (key {:?})", self.msg(), String::from_utf8_lossy(key)),
+            _ => write!(f, "{}", self.msg()),
+        };
     }
 }
 
@@ -47,12 +141,27 @@ impl std::error::Error for Error {
 
     fn cause(&self) -> Option<&std::error::Error> {
         return match self {
-            &Error::IoError(ref e) => Some(e),
-            &Error::MsgError(_) => None,
+            &Error::IoError(ref e, _) => Some(e),
+            _ => None,
         }
     }
 }
 
+// std::io::Error isn't Clone, so the copy of an I/O error only keeps its kind and message.
+impl Clone for Error {
+    fn clone(&self) -> Error {
+        return match self {
+            &Error::IoError(ref e, ref path) => Error::IoError(std::io::Error::new(e.kind(), e.to_string()), path.clone()),
+            &Error::MalformedError(ref s) => Error::MalformedError(s.clone()),
+            &Error::CorruptionError{table_id, offset} => Error::CorruptionError{table_id: table_id, offset: offset},
+            &Error::VersionError{found, expected} => Error::VersionError{found: found, expected: expected},
+            &Error::LockedError(ref path) => Error::LockedError(path.clone()),
+            &Error::InvalidArgumentError(ref s) => Error::InvalidArgumentError(s.clone()),
+            &Error::ConflictError(ref key) => Error::ConflictError(key.clone()),
+        };
+    }
+}
+
 impl From<std::io::Error> for Error {
-    fn from(e: std::io::Error) -> Error { Error::IoError(e) }
+    fn from(e: std::io::Error) -> Error { Error::IoError(e, None) }
 }
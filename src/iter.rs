@@ -2,78 +2,125 @@ use error::*;
 use util::*;
 use disk::TableIterator;
 
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Direction {
     Forward, Backward
 }
 
+// Iterators see the store as of some sequence number, skipping keys with no version at or
+// before it.
 pub trait MutationIterator {
     fn current_key(&self) -> Result<Option<&[u8]>>;
+    // The current key's newest version.
     fn current_value(&mut self) -> Result<Mutation>;
+    // All of the current key's versions, newest first.
+    fn current_versions(&mut self) -> Result<Versions>;
     fn step(&mut self) -> Result<()>;
 }
 
-// NOTE: Iterators should return a batch of elements so we can merge and process
-// them more efficiently.
-pub struct MergeIterator<'a> {
-    // iters and iters_front are parallel arrays.
-    iters: Vec<Box<MutationIterator + 'a>>,
-    // NOTE: This could be a priority queue.
-    iters_front: Vec<Option<Buf>>,
+// A child iterator's current key, ordered so that the frontmost entry is the heap's maximum:
+// the smallest key going forward, the biggest going backward, and among equal keys, the
+// iterator with the lowest index (i.e. the highest precedence).
+struct HeapEntry {
+    key: Buf,
+    index: usize,
     direction: Direction,
 }
 
-fn frontmost_front<'a>(iter: &'a MergeIterator) -> Option<(usize, &'a [u8])> {
-    let ixkeys = iter.iters_front.iter().enumerate()
-        .filter_map(|(i, opt_key)| opt_key.as_ref().map(|k: &'a Vec<u8>| (i, k.as_ref())));
-    if let Direction::Forward = iter.direction {
-        return ixkeys.min_by_key(|&(_, k)| k);
-    } else {
-        // We want the first maximal element to be returned, not the last.  So we add a tie breaker.
-        // (min_by_key returns the first, so we didn't need a tie breaker for that case).
-        let n: usize = iter.iters_front.len();
-        return ixkeys.max_by_key(|&(i, k)| (k, n - i));
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &HeapEntry) -> Ordering {
+        let by_key = match self.direction {
+            Direction::Forward => other.key.cmp(&self.key),
+            Direction::Backward => self.key.cmp(&other.key),
+        };
+        return by_key.then_with(|| other.index.cmp(&self.index));
     }
 }
 
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &HeapEntry) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &HeapEntry) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl Eq for HeapEntry {}
+
+// NOTE: Iterators should return a batch of elements so we can merge and process
+// them more efficiently.
+pub struct MergeIterator<'a> {
+    // In order of precedence -- where two have the same key, the first one's value wins.
+    iters: Vec<Box<MutationIterator + 'a>>,
+    // An entry for every iterator in iters that isn't at its end.
+    heap: BinaryHeap<HeapEntry>,
+    // Scratch space for step, kept to save allocations.
+    stepping: Vec<HeapEntry>,
+}
+
 impl<'a> MergeIterator<'a> {
     pub fn make(mut iters: Vec<Box<MutationIterator + 'a>>, direction: Direction) -> Result<MergeIterator<'a>> {
-        let mut iters_front = Vec::<Option<Buf>>::new();
-        for it in iters.iter_mut() {
-            iters_front.push(it.current_key()?.map(|x| {
-                x.to_vec()
-            }));
+        let mut heap = BinaryHeap::<HeapEntry>::with_capacity(iters.len());
+        for (i, it) in iters.iter_mut().enumerate() {
+            if let Some(key) = it.current_key()? {
+                heap.push(HeapEntry{key: key.to_vec(), index: i, direction: direction});
+            }
         }
         return Ok(MergeIterator{
             iters: iters,
-            iters_front: iters_front,
-            direction: direction,
+            heap: heap,
+            stepping: Vec::new(),
         });
     }
 }
 
 impl<'a> MutationIterator for MergeIterator<'a> {
     fn current_key(&self) -> Result<Option<&[u8]>> {
-        let ret = Ok(frontmost_front(&self).map(|(_, k)| k));
-        return ret;
+        return Ok(self.heap.peek().map(|e| &e.key as &[u8]));
     }
     fn current_value(&mut self) -> Result<Mutation> {
-        if let Some((i, _)) = frontmost_front(&self) {
-            return self.iters[i].current_value();
+        if let Some(index) = self.heap.peek().map(|e| e.index) {
+            return self.iters[index].current_value();
         } else {
-            return mk_err("current_value called on empty MutationIterator");
+            return invalid_arg_err("current_value called on empty MutationIterator");
         }
     }
-    fn step(&mut self) -> Result<()> {
-        let frontmost: Buf = {
-            let (_, key) = frontmost_front(&self).or_err("step MergeIterator too far")?;
-            key.to_vec()  // NOTE: Sigh on the copying.
+    fn current_versions(&mut self) -> Result<Versions> {
+        // Every iterator at the current key has versions of it, and those of an iterator
+        // with higher precedence are newer.
+        let mut indexes: Vec<usize> = match self.heap.peek() {
+            Some(front) => self.heap.iter().filter(|e| e.key == front.key).map(|e| e.index).collect(),
+            None => return invalid_arg_err("current_versions called on empty MutationIterator"),
         };
-        for i in 0..self.iters.len() {
-            if self.iters_front[i].as_ref() == Some(&frontmost) {
-                self.iters[i].step()?;
-                // NOTE: Maybe we could avoid copying here too.
-                self.iters_front[i] = self.iters[i].current_key()?.map(|x| x.to_vec());
+        indexes.sort();
+        let mut ret = Versions::new();
+        for index in indexes {
+            ret.extend(self.iters[index].current_versions()?);
+        }
+        return Ok(ret);
+    }
+    fn step(&mut self) -> Result<()> {
+        // Step every iterator that's at the frontmost key, reusing their entries' buffers.
+        let front: HeapEntry = self.heap.pop().or_invalid_arg("step MergeIterator too far")?;
+        while self.heap.peek().is_some_and(|e| e.key == front.key) {
+            let entry = self.heap.pop().unwrap();
+            self.stepping.push(entry);
+        }
+        self.stepping.push(front);
+        while let Some(mut entry) = self.stepping.pop() {
+            let it = &mut self.iters[entry.index];
+            it.step()?;
+            if let Some(key) = it.current_key()? {
+                entry.key.clear();
+                entry.key.extend_from_slice(key);
+                self.heap.push(entry);
             }
         }
         return Ok(());
@@ -117,7 +164,14 @@ impl<'a> MutationIterator for ConcatIterator<'a> {
         if let Some(&mut (_, ref mut iter)) = self.current.as_mut() {
             return iter.current_value();
         } else {
-            return mk_err("current_value called on empty ConcatIterator");
+            return invalid_arg_err("current_value called on empty ConcatIterator");
+        }
+    }
+    fn current_versions(&mut self) -> Result<Versions> {
+        if let Some(&mut (_, ref mut iter)) = self.current.as_mut() {
+            return iter.current_versions();
+        } else {
+            return invalid_arg_err("current_versions called on empty ConcatIterator");
         }
     }
     fn step(&mut self) -> Result<()> {
@@ -136,9 +190,77 @@ impl<'a> MutationIterator for ConcatIterator<'a> {
                 }
             }
         } else {
-            return mk_err("step called on empty ConcatIterator");
+            return invalid_arg_err("step called on empty ConcatIterator");
         }
         self.current = None;
         return Ok(());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Iterates over (key, value) pairs, in the order given, each with the same sequence number.
+    struct VecIterator {
+        entries: Vec<(Buf, Buf)>,
+        pos: usize,
+        seq: SeqNum,
+    }
+
+    impl MutationIterator for VecIterator {
+        fn current_key(&self) -> Result<Option<&[u8]>> {
+            return Ok(self.entries.get(self.pos).map(|e| &e.0 as &[u8]));
+        }
+        fn current_value(&mut self) -> Result<Mutation> {
+            return Ok(Mutation::Set(self.entries[self.pos].1.clone()));
+        }
+        fn current_versions(&mut self) -> Result<Versions> {
+            return Ok(vec![(self.seq, self.current_value()?)]);
+        }
+        fn step(&mut self) -> Result<()> {
+            self.pos += 1;
+            return Ok(());
+        }
+    }
+
+    fn vec_iter<'a>(keys: &[&str], value: &str, seq: SeqNum, direction: Direction) -> Box<MutationIterator + 'a> {
+        let mut entries: Vec<(Buf, Buf)> = keys.iter().map(|k| (k.as_bytes().to_vec(), value.as_bytes().to_vec())).collect();
+        if direction == Direction::Backward {
+            entries.reverse();
+        }
+        return Box::new(VecIterator{entries: entries, pos: 0, seq: seq});
+    }
+
+    #[test]
+    fn merge_precedence() {
+        for &direction in [Direction::Forward, Direction::Backward].iter() {
+            let iters = vec![
+                vec_iter(&["b", "d"], "first", 4, direction),
+                vec_iter(&[], "empty", 3, direction),
+                vec_iter(&["a", "b", "c", "d"], "second", 2, direction),
+                vec_iter(&["a", "d", "e"], "third", 1, direction),
+            ];
+            let mut merge = MergeIterator::make(iters, direction).unwrap();
+            let mut got = Vec::<(Buf, Buf)>::new();
+            let mut got_seqs = Vec::<Vec<SeqNum>>::new();
+            while let Some(key) = merge.current_key().unwrap().map(|k| k.to_vec()) {
+                got_seqs.push(merge.current_versions().unwrap().iter().map(|&(seq, _)| seq).collect());
+                if let Mutation::Set(value) = merge.current_value().unwrap() {
+                    got.push((key, value));
+                }
+                merge.step().unwrap();
+            }
+            if direction == Direction::Backward {
+                got.reverse();
+                got_seqs.reverse();
+            }
+            let expected: Vec<(Buf, Buf)> = [("a", "second"), ("b", "first"), ("c", "second"), ("d", "first"), ("e", "third")]
+                .iter().map(|&(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec())).collect();
+            assert_eq!(expected, got);
+            // Each key's versions come from every iterator holding it, in order of precedence.
+            assert_eq!(vec![vec![2, 1], vec![4, 2], vec![2], vec![4, 2, 1], vec![1]], got_seqs);
+            assert!(merge.step().is_err());
+        }
+    }
+}
@@ -21,35 +21,111 @@
 //! std::fs::remove_dir_all(dir).unwrap();
 //! ```
 
+use std::collections::BTreeSet;
 use std::collections::Bound;
+use std::collections::VecDeque;
 use std::iter::*;
+use std::sync::Arc;
+use std::sync::Mutex;
 
-extern crate owning_ref;
+extern crate crc;
 extern crate rand;
 extern crate libc;
 extern crate fnv;
 
+mod batch;
+pub use batch::WriteBatch;
+mod block;
+mod bloom;
+use bloom::*;
+mod cache;
+use cache::*;
+pub use cache::CacheStats;
+mod compaction;
+use compaction::*;
+mod compress;
 mod disk;
 use disk::*;
 mod encoding;
 mod error;
 use error::*;
+pub use error::{Error, ErrorKind};
 mod iter;
 use iter::*;
+mod lock;
+use lock::*;
 mod memstore;
 use memstore::*;
+mod mmap;
+mod options;
+pub use options::*;
+mod shared;
+pub use shared::SharedStore;
+mod snapshot;
+use snapshot::*;
+pub use snapshot::Snapshot;
 mod toc;
 use toc::*;
+mod transaction;
+pub use transaction::Transaction;
+pub use toc::OrphanReport;
 mod util;
 use util::*;
+mod wal;
+use wal::*;
+
+// Level zero gets releveled once it has more tables than this.
+const LEVEL_ZERO_TABLES: usize = 4;
+
+// What a write has to wait for before it goes ahead, while the compaction thread is busy.
+enum WriteDelay {
+    // Level zero is filling up:  the write sleeps a moment, to give the thread a head start.
+    Slowdown,
+    // There's no room for another immutable memstore, or level zero is full:  the write
+    // waits for the job to finish.
+    Stall(JobWaiter),
+}
+
+// A full memstore, waiting for the compaction thread to flush it.
+struct Immutable {
+    ms: Arc<MemStore>,
+    // The logs holding its contents, which can go once it's in a table.
+    logs: Vec<LogNumber>,
+    // The last of them, if it might have unsynced records.
+    log: Option<Wal>,
+}
 
 pub struct Store {
-    // Never empty
-    memstores: Vec<MemStore>,
+    // The memstore we write to.
+    memstore: MemStore,
+    // Full memstores, oldest first.  The compaction thread flushes them in that order.
+    immutables: VecDeque<Immutable>,
     threshold: usize,
     directory: String,
     toc_file: std::fs::File,
     toc: Toc,
+    // The bloom filter of every table in the toc.
+    filters: fnv::FnvHashMap<TableId, Buf>,
+    // Shared with every TableIterator we make.
+    table_cache: SharedTableCache,
+    block_cache: SharedBlockCache,
+    // The log that mutations to the memstore get appended to.
+    log: Wal,
+    // The sequence number of the latest write.
+    last_seq: SeqNum,
+    snapshots: LiveSnapshots,
+    compactor: Compactor,
+    // The first failure of a compaction job, or of recording its tables.  Once there is one,
+    // writes and flushes fail with it.
+    background_error: Option<Error>,
+    // Set for a store behind a SharedStore, which waits out write delays before taking the
+    // lock.  Writes under the lock never wait; if they lose a race with other writers for
+    // room, the memstore just grows past the threshold until there's room.
+    delays_outside: bool,
+    options: Options,
+    orphan_report: OrphanReport,
+    // Held for as long as the store is open.
+    _lock: DirLock,
 }
 
 pub struct StoreIter<'a> {
@@ -61,36 +137,103 @@ pub struct StoreIter<'a> {
 impl Store {
     /// Creates a new store in a new directory.
     pub fn create(dir: &str) -> Result<()> {
-        // NOTE: We'll want directory locking and such.
-        std::fs::create_dir(dir)?;
+        std::fs::create_dir(dir).at_path(dir)?;
         create_toc(dir)?;
         return Ok(());
     }
 
-    /// Opens the store.
+    /// Opens the store.  Fails with `LockedError` if another `Store` has it open.
     ///
-    /// `threshold` is an upper bound on the size of unflushed data.
+    /// `threshold` is the size at which the memstore of unflushed writes gets queued
+    /// for flushing, and a new one started.  Up to `Options::max_immutable_memstores`
+    /// of them can be waiting at once.
     pub fn open(dir: &str, threshold: usize) -> Result<Store> {
-        let (toc_file, toc) = read_toc(dir)?;
-        return Ok(Store::make_existing(threshold, dir.to_string(), toc_file, toc, MemStore::new()));
+        return Store::open_with_options(dir, threshold, Options::default());
+    }
+
+    /// Opens the store, with non-default options.
+    pub fn open_with_options(dir: &str, threshold: usize, options: Options) -> Result<Store> {
+        if options.level_base_size == 0 || options.level_size_multiplier < 2 {
+            return invalid_arg_err("level sizes have to grow from a nonzero base");
+        }
+        // Lock before touching anything -- read_toc can truncate the toc.
+        let lock = lock_directory(dir)?;
+        let (toc_file, toc, toc_truncated) = read_toc(dir)?;
+        let orphan_report = collect_orphans(dir, &toc, toc_truncated, options.quarantine_orphans)?;
+        let mut filters = fnv::FnvHashMap::default();
+        let mut last_seq: SeqNum = 0;
+        for (&table_id, ti) in toc.table_infos.iter() {
+            let (filter, largest_seq) = load_filter(dir, ti)?;
+            filters.insert(table_id, filter);
+            last_seq = last_seq.max(largest_seq);
+        }
+        // Mutations that never made it into a table are replayed from the logs.
+        let (old_logs, ms, log_seq) = recover_logs(dir)?;
+        let log = create_log(dir, old_logs.last().map_or(0, |&n| n + 1))?;
+        let mut store = Store::make_existing(threshold, dir.to_string(), toc_file, toc, log, options, lock)?;
+        store.orphan_report = orphan_report;
+        store.filters = filters;
+        store.last_seq = last_seq.max(log_seq);
+        if !old_logs.is_empty() {
+            store.immutables.push_back(Immutable{ms: Arc::new(ms), logs: old_logs, log: None});
+        }
+        store.schedule_compaction()?;
+        return Ok(store);
     }
 
-    fn make_existing(threshold: usize, directory: String, toc_file: std::fs::File, toc: Toc, ms: MemStore) -> Store {
-        return Store{
-            memstores: vec![MemStore::new(), ms],
+    fn make_existing(threshold: usize, directory: String, toc_file: std::fs::File, toc: Toc,
+                     log: Wal, options: Options, lock: DirLock) -> Result<Store> {
+        let compactor = Compactor::spawn(&directory, threshold, &options)?;
+        return Ok(Store{
+            memstore: MemStore::new(),
+            immutables: VecDeque::new(),
             threshold: threshold,
 
             directory: directory,
             toc_file: toc_file,
             toc: toc,
-        }
+            filters: fnv::FnvHashMap::default(),
+            table_cache: Arc::new(Mutex::new(TableCache::new(options.table_cache_capacity, options.mmap_tables))),
+            block_cache: Arc::new(Mutex::new(BlockCache::new(options.block_cache_capacity))),
+            log: log,
+            last_seq: 0,
+            snapshots: LiveSnapshots::default(),
+            compactor: compactor,
+            background_error: None,
+            delays_outside: false,
+            options: options,
+            orphan_report: OrphanReport::default(),
+            _lock: lock,
+        });
+    }
+
+    /// Reports how often reads found table blocks in the block cache.
+    pub fn block_cache_stats(&self) -> CacheStats {
+        return self.block_cache.lock().unwrap().stats();
+    }
+
+    /// Reports what `open` did with table files the toc didn't reference.
+    pub fn orphan_report(&self) -> &OrphanReport {
+        return &self.orphan_report;
+    }
+
+    /// Returns the error that a background flush or compaction failed with, if one did.
+    /// Writes and flushes fail with it from then on, before changing anything; reopen the
+    /// store to recover.
+    pub fn background_error(&self) -> Option<&Error> {
+        return self.background_error.as_ref();
     }
 
     /// Inserts a key/value pair into the store if the key is not already present.
     /// Returns true if an insertion happened.
     pub fn insert(&mut self, key: &[u8], val: &[u8]) -> Result<bool> {
+        return self.insert_opt(key, val, &WriteOptions::default());
+    }
+
+    /// Like `insert`, with the given write options.
+    pub fn insert_opt(&mut self, key: &[u8], val: &[u8], opts: &WriteOptions) -> Result<bool> {
         if !self.exists(key)? {
-            self.put(key, val)?;
+            self.put_opt(key, val, opts)?;
             return Ok(true);
         }
         return Ok(false);
@@ -99,8 +242,13 @@ impl Store {
     /// Replaces an existing key/value pair in the store.  If the key is not present,
     /// does nothing and returns false.
     pub fn replace(&mut self, key: &[u8], val: &[u8]) -> Result<bool> {
+        return self.replace_opt(key, val, &WriteOptions::default());
+    }
+
+    /// Like `replace`, with the given write options.
+    pub fn replace_opt(&mut self, key: &[u8], val: &[u8], opts: &WriteOptions) -> Result<bool> {
         if self.exists(key)? {
-            self.put(key, val)?;
+            self.put_opt(key, val, opts)?;
             return Ok(true);
         }
         return Ok(false);
@@ -109,15 +257,62 @@ impl Store {
     /// Puts a key/value pair into the store, replacing the value if the key is
     /// already present.  Compare to `insert` or `replace`.
     pub fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
-        self.memstores[0].apply(key.to_vec(), Mutation::Set(val.to_vec()));
-        return self.consider_split();
+        return self.put_opt(key, val, &WriteOptions::default());
+    }
+
+    /// Like `put`, with the given write options.
+    pub fn put_opt(&mut self, key: &[u8], val: &[u8], opts: &WriteOptions) -> Result<()> {
+        self.consider_split()?;
+        return self.apply(vec![(key.to_vec(), Mutation::Set(val.to_vec()))], opts);
+    }
+
+    /// Applies the batch's puts and removes together.  Readers see all of them or none,
+    /// and so does `open` after a crash.
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        return self.write_opt(batch, &WriteOptions::default());
+    }
+
+    /// Like `write`, with the given write options.
+    pub fn write_opt(&mut self, batch: WriteBatch, opts: &WriteOptions) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.consider_split()?;
+        return self.apply(batch.into_ops(), opts);
+    }
+
+    // Applies the batch, unless one of the keys in `read` has been written since the
+    // snapshot.  Whether it does is decided before it's logged, so an error means the batch
+    // wasn't written (unless syncing the log failed).
+    fn write_unless_changed(&mut self, batch: WriteBatch, read: &BTreeSet<Buf>, snapshot: &Snapshot,
+                            opts: &WriteOptions) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.consider_split()?;
+        let seq: SeqNum = self.read_seq(&ReadOptions{snapshot: Some(snapshot)})?;
+        for key in read.iter() {
+            // The newest version of a key always survives compaction, so this catches
+            // every write to it.
+            if let Some((written, _)) = self.lookup(key, self.last_seq)? {
+                if written > seq {
+                    return Err(Error::ConflictError(key.clone()));
+                }
+            }
+        }
+        return self.apply(batch.into_ops(), opts);
     }
 
     /// Removes a key/value pair from the store. Returns true if the key was present.
     pub fn remove(&mut self, key: &[u8]) -> Result<bool> {
+        return self.remove_opt(key, &WriteOptions::default());
+    }
+
+    /// Like `remove`, with the given write options.
+    pub fn remove_opt(&mut self, key: &[u8], opts: &WriteOptions) -> Result<bool> {
         if self.exists(key)? {
-            self.memstores[0].apply(key.to_vec(), Mutation::Delete);
             self.consider_split()?;
+            self.apply(vec![(key.to_vec(), Mutation::Delete)], opts)?;
             return Ok(true);
         }
         return Ok(false);
@@ -126,96 +321,247 @@ impl Store {
     /// Ensures that all preceding write operations have been written
     /// to disk (if you trust your kernel and your disk).
     pub fn sync(&mut self) -> Result<()> {
-        // NOTE: We could, instead, sync file by file.
-        use libc;
-        self.flush()?;
-        unsafe {
-            libc::sync();
+        // Anything not in a durable table is in the current log, or the logs of the
+        // immutable memstores.
+        for imm in self.immutables.iter_mut() {
+            if let Some(ref mut log) = imm.log {
+                if log.unsynced > 0 {
+                    log.sync()?;
+                }
+            }
+        }
+        return self.log.sync();
+    }
+
+    // Logs the mutations as one record, then applies them to the active memstore, all with
+    // the next sequence number.  Once the record is logged, the write has happened:  if
+    // syncing the log fails, the error only means it might not be durable.
+    fn apply(&mut self, ops: Vec<(Buf, Mutation)>, opts: &WriteOptions) -> Result<()> {
+        let seq: SeqNum = self.last_seq + 1;
+        self.log.append(seq, &ops)?;
+        let snapshots: Vec<SeqNum> = live_snapshots(&self.snapshots);
+        for (key, m) in ops {
+            self.memstore.apply(key, seq, m, &snapshots);
+        }
+        self.last_seq = seq;
+        let sync: bool = opts.sync || match self.options.sync_mode {
+            SyncMode::NoSync => false,
+            SyncMode::GroupCommit(n) => self.log.unsynced >= n,
+            SyncMode::SyncPerWrite => true,
+        };
+        if sync {
+            self.sync()?;
         }
         return Ok(());
     }
 
-    /// Flushes any buffered write operations to disk.
+    /// Takes a snapshot of the store, for reading it as it is now even after later writes.
+    /// Compactions keep older data around for as long as the snapshot lives, so drop it
+    /// once you're done with it.
+    pub fn snapshot(&self) -> Snapshot {
+        return Snapshot::take(&self.snapshots, self.last_seq);
+    }
+
+    // The sequence number that the read sees the store as of.
+    fn read_seq(&self, opts: &ReadOptions) -> Result<SeqNum> {
+        if let Some(snapshot) = opts.snapshot {
+            if !snapshot.belongs_to(&self.snapshots) {
+                return invalid_arg_err("snapshot of a different store");
+            }
+            return Ok(snapshot.seq());
+        }
+        return Ok(self.last_seq);
+    }
+
+    /// Flushes any buffered write operations to disk, and waits for the compactions
+    /// that follow.
     pub fn flush(&mut self) -> Result<()> {
-        let ms: MemStore = self.memstores.remove(0);
+        self.start_flush()?;
+        while self.compactor.busy() {
+            self.poll_compaction(true)?;
+        }
+        return Ok(());
+    }
 
-        // NOTE: Instead of flushing and compacting, we could, you know, do a
-        // flush into the compaction.
-        self.flush_and_record(0, &ms)?;
-        self.rebalance()?;
+    // Queues the memstore for flushing, and starts the compactions.
+    fn start_flush(&mut self) -> Result<()> {
+        self.check_background_error()?;
+        if !self.memstore.entries.is_empty() {
+            self.freeze_memstore()?;
+        }
+        return self.schedule_compaction();
+    }
 
-        self.memstores.insert(0, MemStore::new());
+    // Queues the memstore for flushing, with a new one (and a new log) taking its place.
+    fn freeze_memstore(&mut self) -> Result<()> {
+        // Everything in the memstore is in the current log.  Switch to a new log, so that
+        // the old one can be deleted once the memstore is in a table.
+        let new_log = create_log(&self.directory, self.log.number + 1)?;
+        let old_log = std::mem::replace(&mut self.log, new_log);
+        let ms: MemStore = std::mem::replace(&mut self.memstore, MemStore::new());
+        self.immutables.push_back(Immutable{ms: Arc::new(ms), logs: vec![old_log.number], log: Some(old_log)});
         return Ok(());
     }
 
-    fn rebalance(&mut self) -> Result<()> {
-        if self.toc.level_infos.get(&0).map_or(false, |lz| lz.len() > 4) {
+    // The memstores, newest first, which is their order of precedence.
+    fn memstores<'a>(&'a self) -> impl Iterator<Item = &'a MemStore> + 'a {
+        return std::iter::once(&self.memstore).chain(self.immutables.iter().rev().map(|imm| &*imm.ms));
+    }
+
+    fn level_zero_tables(&self) -> usize {
+        return self.toc.level_infos.get(&0).map_or(0, |ids| ids.len());
+    }
+
+    // How far over its target size the level is.  It's due for releveling once this is over 1.
+    fn level_score(&self, level: LevelNumber) -> f64 {
+        if level == 0 {
+            // Level zero's tables overlap, so what matters is how many of them reads have to check.
+            let num_tables: usize = self.toc.level_infos.get(&0).map_or(0, |ids| ids.len());
+            return num_tables as f64 / LEVEL_ZERO_TABLES as f64;
+        }
+        let size: u64 = self.toc.level_sizes.get(&level).map_or(0, |&size| size);
+        let mut target: u64 = self.options.level_base_size;
+        for _ in 1..level {
+            target = target.saturating_mul(self.options.level_size_multiplier);
+        }
+        return size as f64 / target as f64;
+    }
+
+    // Picks the tables to relevel next, from the level furthest over its target -- if any are.
+    fn pick_compaction(&self) -> Option<(LevelNumber, Vec<TableId>)> {
+        let mut worst: Option<(LevelNumber, f64)> = None;
+        for &level in self.toc.level_infos.keys() {
+            let score: f64 = self.level_score(level);
+            if score > 1.0 && worst.is_none_or(|(_, worst_score)| score > worst_score) {
+                worst = Some((level, score));
+            }
+        }
+        let level: LevelNumber = worst?.0;
+
+        if level == 0 {
             // Do a releveling with all but the latest (highest numbered) table.
             let table_ids: Vec<TableId>
                 = self.toc.level_infos.get(&0).unwrap().iter().rev().skip(1).map(|&x| x).collect();
-            self.relevel(0, table_ids)?;
-            // Exit.  Don't do more than one releveling per "rebalance"
-            // operation.  Just to spread the work out, barely.
-            return Ok(());
+            return Some((0, table_ids));
         }
 
-        // NOTE: We might want to spread out pending necessary relevelings
-        // instead of doing them all in a row.  We might need to do more than
-        // one releveling at a time, in order to keep up with writes, though.
-        // Basically, expect each releveling at level 0 to kick off a bunch of
-        // relevelings at level 1, 2, 3, 4, ...
-
         // NOTE: Maybe relevel a batch of N consecutive files at once, instead
         // of just 1 at a time.  This will minimize overhead of dealing with
-        // edges.  We'd probably have to relevel 4 at a time, no?
-
-        let max_level: LevelNumber
-            = self.toc.level_infos.iter().map(|(&level, _)| level).max().expect("at least one level");
+        // edges.
+
+        // We want to kick out one table for this level.  The one which
+        // overlaps the fewest child tables.
+        // NOTE: A data structure for this would be nice.
+        let mut smallest_overlap = usize::max_value();
+        let mut smallest_overlap_table_id: TableId = TableId(0);
+
+        for &id in self.toc.level_infos.get(&level).expect("toc valid in pick_compaction").iter() {
+            // NOTE: Pass a slice to single TableInfo element without cloning.
+            let infos: [TableInfo; 1]
+                = [self.toc.table_infos.get(&id).expect("toc valid in pick_compaction").clone()];
+            // NOTE: Would be nice not to allocate this vec.  Just count number of overlapping.
+            let lower_overlapping_ids: Vec<_> = Store::get_overlapping_tables(&self.toc, &infos, level + 1);
+            let overlap = lower_overlapping_ids.len();
+            // NOTE: We're biased towards releveling left-most tables given equal overlap.
+            if overlap < smallest_overlap {
+                smallest_overlap = overlap;
+                smallest_overlap_table_id = id;
+            }
+        }
 
-        for level in 1..max_level {
-            let to_relevel: (LevelNumber, TableId);
-            if let Some(table_ids) = self.toc.level_infos.get(&level) {
-                // NOTE: Icky conversion -- change LevelNumber to u32?
-                // NOTE: Should use total file size instead.
-                if table_ids.len() <= 4 * 10usize.pow(level as u32 - 1) {
-                    continue;
-                }
-                // Now what?  We want to kick out one table for this level.  The
-                // one which overlaps the fewest child tables.
-                // NOTE: A data structure for this would be nice.
-                let mut smallest_overlap = usize::max_value();
-                let mut smallest_overlap_table_id: TableId = TableId(0);
-
-                for &id in table_ids.iter() {
-                    // NOTE: Pass a slice to single TableInfo element without cloning.
-                    let infos: [TableInfo; 1]
-                        = [self.toc.table_infos.get(&id).expect("toc valid in rebalance").clone()];
-                    // NOTE: Would be nice not to allocate this vec.  Just count number of overlapping.
-                    let lower_overlapping_ids: Vec<_> = Store::get_overlapping_tables(&self.toc, &infos, level + 1);
-                    let overlap = lower_overlapping_ids.len();
-                    // NOTE: We're biased towards releveling left-most tables given equal overlap.
-                    if overlap < smallest_overlap {
-                        smallest_overlap = overlap;
-                        smallest_overlap_table_id = id;
-                    }
-                }
+        assert!(smallest_overlap != usize::max_value());
+        return Some((level, vec![smallest_overlap_table_id]));
+    }
 
-                assert!(smallest_overlap != usize::max_value());
-                to_relevel = (level, smallest_overlap_table_id);
+    // Starts the compaction thread on the next job, if it's idle and there's work to do.
+    // Flushing immutable memstores comes first, then releveling the level furthest over
+    // its target, until none are.  (Each releveling takes tables out of the level, and
+    // targets grow with depth, so that ends.)
+    fn schedule_compaction(&mut self) -> Result<()> {
+        while !self.compactor.busy() {
+            if let Some(imm) = self.immutables.front() {
+                let job = Job::Flush(imm.ms.clone());
+                self.compactor.start(job, self.toc.next_table_id, live_snapshots(&self.snapshots))?;
+            } else if let Some((level, tables)) = self.pick_compaction() {
+                self.relevel(level, tables)?;
             } else {
-                continue;
+                break;
             }
-            self.relevel(to_relevel.0, vec![to_relevel.1])?;
         }
+        return Ok(());
+    }
 
+    fn check_background_error(&self) -> Result<()> {
+        if let Some(ref e) = self.background_error {
+            return Err(e.clone());
+        }
         return Ok(());
     }
 
+    // Collects the compaction thread's result if it's finished its job (waiting for it to,
+    // if `wait`), then schedules the next job.  A failed job, or a failure to record its
+    // tables, becomes the background error.
+    fn poll_compaction(&mut self, wait: bool) -> Result<()> {
+        self.check_background_error()?;
+        if let Some(result) = self.compactor.finished(wait) {
+            if let Err(e) = result.and_then(|finished| self.install_compaction(finished)) {
+                self.background_error = Some(e.clone());
+                return Err(e);
+            }
+        }
+        return self.schedule_compaction();
+    }
+
+    // Records a finished job's tables in the toc, and deletes what they replace.
+    fn install_compaction(&mut self, finished: Finished) -> Result<()> {
+        let mut error: Option<Error> = None;
+        for (table_id, filter) in finished.filters {
+            self.filters.insert(table_id, filter);
+        }
+        if !finished.additions.is_empty() || !finished.removals.is_empty() {
+            let entry = Entry{
+                additions: finished.additions,
+                removals: finished.removals,
+                next_table_id: self.toc.next_table_id,
+            };
+
+            // to_delete will be the same as the removals, but this is more robust against
+            // tweaks to our logic (such as fine-grained treatment of non-overlapping tables
+            // in level 0).
+            let to_delete = append_toc(&self.directory, &mut self.toc, &mut self.toc_file, entry)?;
+            // The toc entry is committed, so from here on, failures mustn't stop us from
+            // bringing the in-memory state up to date.  We report the first one at the end.
+            // (Files we fail to delete get collected as orphans on open.)
+            for table_id in to_delete {
+                self.filters.remove(&table_id);
+                self.table_cache.lock().unwrap().remove(table_id);
+                self.block_cache.lock().unwrap().remove_table(table_id);
+                let path: String = table_filepath(&self.directory, table_id);
+                note_cleanup_error(&mut error, std::fs::remove_file(&path).at_path(&path));
+            }
+        }
+        if finished.flushed {
+            // The memstore is in a durable table now, so its logs can go.
+            let imm: Immutable = self.immutables.pop_front().expect("flushed without immutable memstores");
+            for &number in imm.logs.iter() {
+                note_cleanup_error(&mut error, remove_log(&self.directory, number));
+            }
+        }
+        let synced: Result<()> = sync_directory(&self.directory);
+        return match error {
+            Some(e) => Err(e),
+            None => synced,
+        };
+    }
+
     // 'tables' is in order of precedence, such that frontmost tables supercede
     // later tables when merged.  (They're in reverse order by table number, if
     // in level zero.  In other levels, there's only one table, and even if there
     // was more than one, they'd have non-overlapping key ranges.)
-    fn relevel<'a>(&'a mut self, level: LevelNumber, tables: Vec<TableId>) -> Result<()> {
+    //
+    // Unless the tables can just move down a level, this starts the compaction thread
+    // merging them, so the compaction thread has to be idle.
+    fn relevel(&mut self, level: LevelNumber, tables: Vec<TableId>) -> Result<()> {
         assert!(if level == 0 { tables.len() > 0 } else { tables.len() == 1 });
 
         // What to do:  Go to the next level, find which tables overlap.
@@ -226,91 +572,25 @@ impl Store {
         // NOTE: When releveling 0 -> 1, it's possible there are no overlapping tables.
         if lower_overlapping_ids.is_empty() && !Store::self_overlaps(&table_infos) {
             let additions: Vec<TableInfo>
-                = table_infos.into_iter().map(|x: TableInfo| TableInfo{level: level, .. x}).collect();
+                = table_infos.into_iter().map(|x: TableInfo| TableInfo{level: level + 1, .. x}).collect();
             let entry = Entry{
                 removals: tables,
                 additions: additions,
+                next_table_id: self.toc.next_table_id,
             };
 
-            append_toc(&mut self.toc, &mut self.toc_file, entry)?;
-            return Ok(());
-        } else {
-            let mut iters: Vec<Box<MutationIterator + 'a>> = Vec::new();
-            // NOTE: We might want a smarter iterator for the lower level --
-            // open only one table file at a time, instead of generically
-            // merging the non-overlapping tables together.
-
-            // Add upper level's tables in 'tables' existing order (which is in order of precedence).
-            // Order of lower level's tables doesn't matter, since they're non-overlapping.
-            for table_id in tables.iter().chain(lower_overlapping_ids.iter()) {
-                let interval = Interval{lower: Bound::Unbounded, upper: Bound::Unbounded};
-                self.add_table_iter_to_iters(&mut iters, *table_id, &interval, Direction::Forward)?;
-            }
-
-            let mut iter = MergeIterator::make(iters, Direction::Forward)?;
-
-            // Now we've got a store iter.  Iterate the store iter, building a set of tables.
-
-            let mut additions: Vec<TableInfo> = Vec::new();
-
-            'outer: loop {
-                let mut builder = TableBuilder::new();
-                'inner: loop {
-                    // NOTE: It would be nice to avoid cloning the key here.
-                    if let Some(key) = iter.current_key()?.map(|x| x.to_vec()) {
-                        let mutation = iter.current_value()?;
-                        builder.add_mutation(&key, &mutation);
-                        iter.step()?;
-                        if builder.lowerbound_file_size() > self.threshold {
-                            break 'inner;
-                        }
-                    } else {
-                        if builder.is_empty() {
-                            break 'outer;
-                        } else {
-                            break 'inner;
-                        }
-                    }
-                }
-
-                // We've got a non-empty builder.  Flush it to disk.
-                let table_id = TableId(self.toc.next_table_id);
-                self.toc.next_table_id += 1;
-
-                let mut f = std::fs::File::create(table_filepath(&self.directory, table_id))?;
-                let (keys_offset, file_size, smallest, biggest) = builder.finish(&mut f)?;
-                additions.push(TableInfo{
-                    id: table_id,
-                    level: level + 1,
-                    keys_offset: keys_offset,
-                    file_size: file_size,
-                    smallest_key: smallest,
-                    biggest_key: biggest,
-                });
-            }
-
-            let removals: Vec<TableId>
-                = tables.iter().chain(lower_overlapping_ids.iter()).map(|&x| x).collect();
-
-            let entry = Entry{
-                additions: additions,
-                removals: removals,
-            };
-
-            // to_delete will be the same as 'removals' defined above, but this
-            // is more robust against tweaks to our logic (such as fine-grained
-            // treatment of non-overlapping tables in level 0).
-            let to_delete = append_toc(&mut self.toc, &mut self.toc_file, entry)?;
-            for table_id in to_delete {
-                std::fs::remove_file(table_filepath(&self.directory, table_id))?;
-            }
-
+            append_toc(&self.directory, &mut self.toc, &mut self.toc_file, entry)?;
             return Ok(());
         }
-    }
 
-    fn table_overlaps_interval(x: &TableInfo, y: &Interval<Buf>) -> bool {
-        return !(!above_lower_bound(&x.biggest_key, &y.lower) || !below_upper_bound(&x.smallest_key, &y.upper));
+        // Upper level's tables go in 'tables' existing order (which is in order of precedence).
+        // Order of lower level's tables doesn't matter, since they're non-overlapping.
+        let mut inputs: Vec<TableInfo> = table_infos;
+        for table_id in lower_overlapping_ids.iter() {
+            inputs.push(self.toc.table_infos.get(table_id).expect("toc valid in relevel").clone());
+        }
+        let job = Job::Merge{tables: inputs, level: level + 1};
+        return self.compactor.start(job, self.toc.next_table_id, live_snapshots(&self.snapshots));
     }
 
     fn self_overlaps(xs: &[TableInfo]) -> bool {
@@ -328,121 +608,138 @@ impl Store {
         return !(x.biggest_key < y.smallest_key || y.biggest_key < x.smallest_key);
     }
 
-    // NOTE: We'd like a better data structure for organizing a level's table by keys.
+    // The tables in the level (which mustn't be zero) overlapping any of the given tables.
     fn get_overlapping_tables(toc: &Toc, tables: &[TableInfo], level: LevelNumber) -> Vec<TableId> {
-        if let Some(level_tables) = toc.level_infos.get(&level) {
-            let mut ret: Vec<TableId> = Vec::new();
-            for id in level_tables {
-                for info in tables {
-                    if Store::tables_overlap(toc.table_infos.get(id).expect("toc valid in get_overlapping_tables"), info) {
-                        ret.push(*id);
-                        break;
-                    }
-                }
-            }
-            return ret;
-        } else {
-            return Vec::new();
+        let mut ret: Vec<TableId> = Vec::new();
+        for info in tables {
+            let interval = Interval{
+                lower: Bound::Included(info.smallest_key.clone()),
+                upper: Bound::Included(info.biggest_key.clone()),
+            };
+            ret.extend(tables_in_interval(toc, level, &interval).iter().map(|ti| ti.id));
         }
+        ret.sort();
+        ret.dedup();
+        return ret;
     }
 
+    // What the next write has to wait for, if anything.
+    fn write_delay(&self) -> Option<WriteDelay> {
+        let waiter: JobWaiter = self.compactor.waiter()?;
+        if self.memstore.mem_usage >= self.threshold
+            && (self.immutables.len() >= self.options.max_immutable_memstores
+                || self.level_zero_tables() >= self.options.level_zero_stop_tables) {
+            return Some(WriteDelay::Stall(waiter));
+        }
+        if self.level_zero_tables() >= self.options.level_zero_slowdown_tables {
+            // Rather than stalling writes all at once when level zero fills up.
+            return Some(WriteDelay::Slowdown);
+        }
+        return None;
+    }
+
+    // Called before each write, so that the write either fails without being logged or
+    // succeeds.  (The memstore can go over the threshold by one write.)
     fn consider_split(&mut self) -> Result<()> {
-        if self.memstores[0].mem_usage >= self.threshold {
-            self.flush()?;
+        self.poll_compaction(false)?;
+        if !self.delays_outside {
+            match self.write_delay() {
+                Some(WriteDelay::Slowdown) => std::thread::sleep(std::time::Duration::from_millis(1)),
+                Some(WriteDelay::Stall(_)) => {
+                    while let Some(WriteDelay::Stall(_)) = self.write_delay() {
+                        self.poll_compaction(true)?;
+                    }
+                },
+                None => (),
+            }
+        }
+        if self.memstore.mem_usage >= self.threshold {
+            if let Some(WriteDelay::Stall(_)) = self.write_delay() {
+                return Ok(());
+            }
+            self.freeze_memstore()?;
+            self.schedule_compaction()?;
         }
         return Ok(());
     }
 
-    fn flush_and_record(&mut self, level: LevelNumber, ms: &MemStore) -> Result<()> {
-        if ms.entries.is_empty() {
-            return Ok(());
+    // Looks up the key in the table, unless the table's bloom filter rules it out.
+    fn lookup_table(&self, ti: &TableInfo, key: &[u8], snapshot: SeqNum) -> Result<Option<(SeqNum, Mutation)>> {
+        if let Some(filter) = self.filters.get(&ti.id) {
+            if !filter_may_contain(filter, key) {
+                return Ok(None);
+            }
         }
-        let table_id = TableId(self.toc.next_table_id);
-        self.toc.next_table_id += 1;
-        let (keys_offset, file_size, smallest, biggest) = flush_to_disk(&self.directory, table_id, &ms)?;
-        let ti = TableInfo{
-            id: table_id,
-            level: level,
-            keys_offset: keys_offset,
-            file_size: file_size,
-            smallest_key: smallest,
-            biggest_key: biggest,
-        };
-        append_toc(&mut self.toc, &mut self.toc_file, Entry{additions: vec![ti], removals: vec![]})?;
-        return Ok(());
+        return lookup_table(&self.directory, ti, key, snapshot, &self.table_cache, &self.block_cache);
     }
 
-    /// Returns true if a key/value pair is present, for the given key.
-    pub fn exists(&mut self, key: &[u8]) -> Result<bool> {
-        for store in self.memstores.iter() {
-            if let Some(m) = store.lookup(key) {
-                return Ok(match m {
-                    &Mutation::Set(_) => true,
-                    &Mutation::Delete => false,
-                });
-            }
-        }
-
-        for (_level, table_ids) in self.toc.level_infos.iter() {
-            // For level zero, we want to iterate tables in reverse order.
-            for table_id in table_ids.iter().rev() {
-                let ti: &TableInfo = self.toc.table_infos.get(table_id).expect("invalid toc");
-                if key >= &ti.smallest_key && key <= &ti.biggest_key {
-                    // NOTE: We'll want to use exists_table.
-                    let opt_mut = lookup_table(&self.directory, ti, key)?;
-                    if let Some(m) = opt_mut {
-                        return Ok(match m {
-                            Mutation::Set(_) => true,
-                            Mutation::Delete => false,
-                        });
+    // Looks the key up in the tables in order of precedence:  level zero's tables, newest
+    // first, then the one table in each other level whose key range holds the key.
+    fn lookup_tables(&self, key: &[u8], snapshot: SeqNum) -> Result<Option<(SeqNum, Mutation)>> {
+        for (&level, table_ids) in self.toc.level_infos.iter() {
+            if level == 0 {
+                for table_id in table_ids.iter().rev() {
+                    let ti: &TableInfo = self.toc.table_infos.get(table_id).expect("invalid toc");
+                    if key >= &ti.smallest_key && key <= &ti.biggest_key {
+                        if let Some(m) = self.lookup_table(ti, key, snapshot)? {
+                            return Ok(Some(m));
+                        }
                     }
                 }
+            } else if let Some(ti) = table_for_key(&self.toc, level, key) {
+                if let Some(m) = self.lookup_table(ti, key, snapshot)? {
+                    return Ok(Some(m));
+                }
+            }
+        }
+        return Ok(None);
+    }
 
+    // The newest version of the key at or before the snapshot, from wherever it is.
+    fn lookup(&self, key: &[u8], snapshot: SeqNum) -> Result<Option<(SeqNum, Mutation)>> {
+        for store in self.memstores() {
+            if let Some(version) = store.lookup(key, snapshot) {
+                return Ok(Some(version.clone()));
             }
         }
+        return self.lookup_tables(key, snapshot);
+    }
 
-        return Ok(false);
+    /// Returns true if a key/value pair is present, for the given key.
+    pub fn exists(&self, key: &[u8]) -> Result<bool> {
+        return self.exists_opt(key, &ReadOptions::default());
+    }
+
+    /// Like `exists`, with the given read options.
+    pub fn exists_opt(&self, key: &[u8], opts: &ReadOptions) -> Result<bool> {
+        // NOTE: We'll want to use exists_table.
+        return Ok(match self.lookup(key, self.read_seq(opts)?)? {
+            Some((_, Mutation::Set(_))) => true,
+            Some((_, Mutation::Delete)) | None => false,
+        });
     }
 
     /// Gets the value for the specified key/value pair, or `None` if the key
     /// does not exist.
-    pub fn get(&mut self, key: &[u8]) -> Result<Option<Buf>> {
-        // NOTE: This doesn't need to be &_mut_ self, because using a StoreIter isn't.
-        for store in self.memstores.iter() {
-            if let Some(m) = store.lookup(key) {
-                return Ok(match m {
-                    &Mutation::Set(ref x) => Some(x.clone()),
-                    &Mutation::Delete => None,
-                });
-            }
-        }
-
-        for (_level, table_ids) in self.toc.level_infos.iter() {
-            // For level zero, we want to iterate tables in reverse order.
-            // NOTE: For other levels, we don't want to iterate at all.  Too much CPU.
-            for table_id in table_ids.iter().rev() {
-                let ti: &TableInfo = self.toc.table_infos.get(table_id).expect("invalid toc");
-                if key >= &ti.smallest_key && key <= &ti.biggest_key {
-                    let opt_mut = lookup_table(&self.directory, ti, key)?;
-                    if let Some(m) = opt_mut {
-                        return Ok(match m {
-                            Mutation::Set(x) => Some(x),
-                            Mutation::Delete => None,
-                        });
-                    }
-                }
-            }
-        }
+    pub fn get(&self, key: &[u8]) -> Result<Option<Buf>> {
+        return self.get_opt(key, &ReadOptions::default());
+    }
 
-        return Ok(None);
+    /// Like `get`, with the given read options.
+    pub fn get_opt(&self, key: &[u8], opts: &ReadOptions) -> Result<Option<Buf>> {
+        return Ok(match self.lookup(key, self.read_seq(opts)?)? {
+            Some((_, Mutation::Set(x))) => Some(x),
+            Some((_, Mutation::Delete)) | None => None,
+        });
     }
 
     fn add_table_iter_to_iters<'a>(
         &self, iters: &mut Vec<Box<MutationIterator + 'a>>, table_id: TableId, interval: &Interval<Buf>,
-        direction: Direction
+        direction: Direction, snapshot: SeqNum
     ) -> Result<()> {
         let ti: &TableInfo = self.toc.table_infos.get(&table_id).expect("invalid toc");
-        let iter = TableIterator::make(&self.directory, ti, interval, direction)?;
+        let iter = TableIterator::make(
+            &self.directory, ti, interval, direction, snapshot, &self.table_cache, &self.block_cache)?;
         iters.push(Box::new(iter));
         return Ok(());
     }
@@ -453,10 +750,17 @@ impl Store {
     /// in the given direction.
     pub fn range_directed<'a>(&'a self, interval: &Interval<Buf>, direction: Direction
     ) -> Result<StoreIter<'a>> {
+        return self.range_directed_opt(interval, direction, &ReadOptions::default());
+    }
+
+    /// Like `range_directed`, with the given read options.
+    pub fn range_directed_opt<'a>(&'a self, interval: &Interval<Buf>, direction: Direction, opts: &ReadOptions
+    ) -> Result<StoreIter<'a>> {
+        let snapshot: SeqNum = self.read_seq(opts)?;
         // NOTE: Could short-circuit for empty/one-key interval.
         let mut iters: Vec<Box<MutationIterator + 'a>> = Vec::new();
-        for store in self.memstores.iter() {
-            iters.push(Box::new(MemStoreIterator::<'a>::make(store, interval, direction)));
+        for store in self.memstores() {
+            iters.push(Box::new(MemStoreIterator::<'a>::make(store, interval, direction, snapshot)));
         }
 
         for (level, table_ids) in self.toc.level_infos.iter() {
@@ -464,24 +768,14 @@ impl Store {
                 // Tables overlap, add them in reverse order.
                 for table_id in table_ids.iter().rev() {
                     // NOTE: We could check if the intervals actually overlap.
-                    self.add_table_iter_to_iters(&mut iters, *table_id, &interval, direction)?;
+                    self.add_table_iter_to_iters(&mut iters, *table_id, &interval, direction, snapshot)?;
                 }
             } else {
-                let mut table_infos: Vec<&'a TableInfo> = Vec::new();
-
-                // NOTE: Would be nice to have a data structure ordered by key.
-                for table_id in table_ids.iter() {
-                    let table_info: &TableInfo = self.toc.table_infos.get(table_id).expect("valid toc in range");
-                    if Store::table_overlaps_interval(table_info, interval) {
-                        table_infos.push(table_info);
-                    }
+                let mut table_infos: Vec<&'a TableInfo> = tables_in_interval(&self.toc, *level, interval);
+                if direction == Direction::Backward {
+                    table_infos.reverse();
                 }
 
-                table_infos.sort_unstable_by(|x, y| {
-                    let res = x.smallest_key.cmp(&y.smallest_key);
-                    match direction { Direction::Forward => res, Direction::Backward => res.reverse() }
-                });
-
                 let interval = interval.clone();
                 let mut ti_index = 0;
                 iters.push(Box::new(ConcatIterator::<'a>::make(Box::new(move || {
@@ -490,7 +784,8 @@ impl Store {
                     } else {
                         let ti: &TableInfo = table_infos[ti_index];
                         ti_index += 1;
-                        Some(Box::new(TableIterator::make(&self.directory, ti, &interval, direction)?))
+                        Some(Box::new(TableIterator::make(
+                            &self.directory, ti, &interval, direction, snapshot, &self.table_cache, &self.block_cache)?))
                     })
                 }))?));
             }
@@ -511,6 +806,11 @@ impl Store {
         return self.range_directed(interval, Direction::Forward);
     }
 
+    /// Like `range`, with the given read options.
+    pub fn range_opt<'a>(&'a self, interval: &Interval<Buf>, opts: &ReadOptions) -> Result<StoreIter<'a>> {
+        return self.range_directed_opt(interval, Direction::Forward, opts);
+    }
+
     /// Creates a StoreIter for iterating backwards through the interval.
     pub fn range_descending<'a>(&'a self, interval: &Interval<Buf>) -> Result<StoreIter<'a>> {
         return self.range_directed(interval, Direction::Backward);
@@ -547,6 +847,16 @@ impl Store {
     }
 }
 
+impl Drop for Store {
+    fn drop(&mut self) {
+        // Record whatever the compaction thread finished, so that its tables don't get
+        // collected as orphans next time.  (Anything it hasn't flushed is still in the logs.)
+        if let Some(Ok(finished)) = self.compactor.finished(true) {
+            let _ = self.install_compaction(finished);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::Bound;
@@ -664,7 +974,11 @@ mod tests {
         // Remove one, so that we test Delete entries really do override Set entries.
         let removed: bool = kv.remove(b("11")).unwrap();
         assert!(removed);
-        assert!(1 < kv.memstores.len());
+        // A small threshold can't hold all that, so some of it has moved on to an immutable
+        // memstore or a table.
+        if kv.threshold < 1000 {
+            assert!(!kv.immutables.is_empty() || !kv.toc.table_infos.is_empty());
+        }
     }
 
     fn verify_basic_kv(ts: &mut TestStore) {
@@ -716,6 +1030,355 @@ mod tests {
         assert_eq!(None, ts.kv().get(b("bogus")).unwrap());
     }
 
+    #[test]
+    fn log_recovery() {
+        let mut ts = TestStore::create(100000);
+        write_basic_kv(&mut ts);
+        // Nothing was flushed, so everything comes back from the log.
+        assert!(ts.close().is_some());
+        ts.open(100000);
+        verify_basic_kv(&mut ts);
+        assert_eq!(None, ts.kv().get(b("11")).unwrap());
+        // And again, with the replayed log now an old log.
+        ts.kv().put(b("zz"), b("value-zz")).unwrap();
+        assert!(ts.close().is_some());
+        ts.open(100000);
+        verify_basic_kv(&mut ts);
+        assert_eq!(Some(b("value-zz").to_vec()), ts.kv().get(b("zz")).unwrap());
+    }
+
+    #[test]
+    fn log_torn_tail() {
+        use std::io::Write;
+        let mut ts = TestStore::create(100000);
+        write_basic_kv(&mut ts);
+        let log_number = ts.kv().log.number;
+        let log_path = format!("{}/{}.log", ts.directory, log_number);
+        assert!(ts.close().is_some());
+        {
+            // Simulate a crash in the middle of appending a record.
+            let mut f = std::fs::OpenOptions::new().append(true).open(&log_path).unwrap();
+            f.write_all(&[7, 0, 0, 0, 0, 0, 0, 0, 1, 2]).unwrap();
+        }
+        ts.open(100000);
+        verify_basic_kv(&mut ts);
+        ts.kv().flush().unwrap();
+        assert!(ts.close().is_some());
+        ts.open(100);
+        verify_basic_kv(&mut ts);
+    }
+
+    #[test]
+    fn write_batch() {
+        let mut ts = TestStore::create(100000);
+        ts.kv().put(b("record"), b("old")).unwrap();
+        ts.kv().put(b("index/old"), b("record")).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(b("record"), b("new"));
+        batch.remove(b("index/old"));
+        batch.put(b("index/new"), b("record"));
+        batch.put(b("scratch"), b("1"));
+        batch.remove(b("scratch"));
+        assert_eq!(5, batch.len());
+        ts.kv().write(batch.clone()).unwrap();
+        let check_applied = |kv: &mut Store| {
+            assert_eq!(Some(b("new").to_vec()), kv.get(b("record")).unwrap());
+            assert_eq!(None, kv.get(b("index/old")).unwrap());
+            assert_eq!(Some(b("record").to_vec()), kv.get(b("index/new")).unwrap());
+            assert_eq!(None, kv.get(b("scratch")).unwrap());
+        };
+        check_applied(ts.kv());
+        ts.kv().write(WriteBatch::new()).unwrap();
+
+        // The batch is one log record, so it survives a reopen whole...
+        assert!(ts.close().is_some());
+        ts.open(100000);
+        check_applied(ts.kv());
+
+        // ...and a crash partway through appending it loses all of it.
+        let mut batch = WriteBatch::new();
+        batch.put(b("record"), b("newer"));
+        batch.put(b("index/newer"), b("record"));
+        ts.kv().write(batch).unwrap();
+        let log_number = ts.kv().log.number;
+        let log_path = format!("{}/{}.log", ts.directory, log_number);
+        assert!(ts.close().is_some());
+        {
+            // Simulate a crash in the middle of appending the batch's record.
+            let f = std::fs::OpenOptions::new().write(true).open(&log_path).unwrap();
+            let len: u64 = f.metadata().unwrap().len();
+            f.set_len(len - 3).unwrap();
+        }
+        ts.open(100000);
+        check_applied(ts.kv());
+        assert_eq!(None, ts.kv().get(b("index/newer")).unwrap());
+    }
+
+    fn check_snapshot(kv: &Store, snap: &Snapshot, a: &str) {
+        let opts = ReadOptions{snapshot: Some(snap)};
+        assert_eq!(Some(b(a).to_vec()), kv.get_opt(b("a"), &opts).unwrap());
+        assert_eq!(Some(b("1").to_vec()), kv.get_opt(b("b"), &opts).unwrap());
+        assert!(kv.exists_opt(b("b"), &opts).unwrap());
+        assert_eq!(None, kv.get_opt(b("c"), &opts).unwrap());
+        let all = Interval::<Buf>{lower: Bound::Unbounded, upper: Bound::Unbounded};
+        let expected: Vec<(Buf, Buf)> = vec![(b("a").to_vec(), b(a).to_vec()), (b("b").to_vec(), b("1").to_vec())];
+        let mut iter = kv.range_opt(&all, &opts).unwrap();
+        let mut got: Vec<(Buf, Buf)> = Vec::new();
+        while let Some(kv_pair) = kv.next(&mut iter).unwrap() {
+            got.push(kv_pair);
+        }
+        assert_eq!(expected, got);
+        let mut iter = kv.range_directed_opt(&all, Direction::Backward, &opts).unwrap();
+        got.clear();
+        while let Some(kv_pair) = kv.next(&mut iter).unwrap() {
+            got.push(kv_pair);
+        }
+        got.reverse();
+        assert_eq!(expected, got);
+    }
+
+    #[test]
+    fn snapshots() {
+        let mut ts = TestStore::create(100000);
+        ts.kv().put(b("a"), b("1")).unwrap();
+        ts.kv().put(b("b"), b("1")).unwrap();
+        let snap: Snapshot = ts.kv().snapshot();
+        ts.kv().put(b("a"), b("2")).unwrap();
+        assert!(ts.kv().remove(b("b")).unwrap());
+        ts.kv().put(b("c"), b("2")).unwrap();
+        // The memstore keeps the version of "a" that the snapshot reads.
+        assert_eq!(2, ts.kv().memstore.entries[b("a")].len());
+        check_snapshot(ts.kv(), &snap, "1");
+        assert_eq!(Some(b("2").to_vec()), ts.kv().get(b("a")).unwrap());
+        assert_eq!(None, ts.kv().get(b("b")).unwrap());
+        assert_eq!(Some(b("2").to_vec()), ts.kv().get(b("c")).unwrap());
+
+        // Flushes and merges keep the versions the snapshots need, too.
+        ts.kv().flush().unwrap();
+        check_snapshot(ts.kv(), &snap, "1");
+        let mut snap2: Option<Snapshot> = None;
+        for i in 3..30 {
+            ts.kv().put(b("a"), format!("{}", i).as_bytes()).unwrap();
+            if i == 10 {
+                snap2 = Some(ts.kv().snapshot());
+            }
+            ts.kv().flush().unwrap();
+        }
+        assert!(ts.kv().toc.level_infos.get(&1).is_some_and(|ids| !ids.is_empty()));
+        check_snapshot(ts.kv(), &snap, "1");
+        assert_eq!(Some(b("10").to_vec()),
+                   ts.kv().get_opt(b("a"), &ReadOptions{snapshot: snap2.as_ref()}).unwrap());
+        assert_eq!(Some(b("29").to_vec()), ts.kv().get(b("a")).unwrap());
+
+        // With no snapshots, only the newest version is worth keeping.
+        drop(snap);
+        drop(snap2);
+        ts.kv().put(b("a"), b("30")).unwrap();
+        ts.kv().put(b("a"), b("31")).unwrap();
+        assert_eq!(1, ts.kv().memstore.entries[b("a")].len());
+
+        // Sequence numbers pick up where they left off, even once the logs are gone.
+        ts.kv().flush().unwrap();
+        let last_seq: SeqNum = ts.kv().last_seq;
+        let stale: Snapshot = ts.kv().snapshot();
+        assert!(ts.close().is_some());
+        ts.open(100000);
+        assert_eq!(last_seq, ts.kv().last_seq);
+        let err = ts.kv().get_opt(b("a"), &ReadOptions{snapshot: Some(&stale)}).err().unwrap();
+        assert_eq!(ErrorKind::InvalidArgument, err.kind());
+    }
+
+    #[test]
+    fn garbage_tables() {
+        let mut ts = TestStore::create(100);
+        write_basic_kv(&mut ts);
+        ts.kv().flush().unwrap();
+        assert!(ts.close().is_some());
+        // What a crash mid-flush could leave behind.
+        let stray_table = format!("{}/1000.tab", ts.directory);
+        let stray_tmp = format!("{}/1001.tab.tmp", ts.directory);
+        std::fs::write(&stray_table, b("bogus")).unwrap();
+        std::fs::write(&stray_tmp, b("bogus")).unwrap();
+        ts.open(100);
+        assert!(!std::path::Path::new(&stray_table).exists());
+        assert!(!std::path::Path::new(&stray_tmp).exists());
+        assert_eq!(vec!["1000.tab".to_string()], ts.kv().orphan_report().removed_tables);
+        assert_eq!(vec!["1001.tab.tmp".to_string()], ts.kv().orphan_report().removed_temp_files);
+        verify_basic_kv(&mut ts);
+    }
+
+    #[test]
+    fn corrupt_toc() {
+        let mut ts = TestStore::create(100);
+        write_basic_kv(&mut ts);
+        ts.kv().flush().unwrap();
+        assert!(ts.close().is_some());
+        let toc_path = format!("{}/toc", ts.directory);
+        let count_tables = |dir: &str| std::fs::read_dir(dir).unwrap()
+            .filter(|ent| ent.as_ref().unwrap().file_name().to_str().unwrap().ends_with(".tab")).count();
+        let tables: usize = count_tables(&ts.directory);
+        assert!(tables > 0);
+
+        // A damaged entry in the middle -- here, in the first entry's payload, past the
+        // 8 byte magic and its 12 byte header -- fails open, and no tables get deleted.
+        flip_byte(&toc_path, 8 + 12 + 1);
+        let err = Store::open(&ts.directory, 100).err().unwrap();
+        assert_eq!(ErrorKind::Corruption, err.kind());
+        assert_eq!(tables, count_tables(&ts.directory));
+        flip_byte(&toc_path, 8 + 12 + 1);
+
+        // A torn entry at the end is what a crash mid-append leaves, and gets truncated.
+        let toc_size: u64 = std::fs::metadata(&toc_path).unwrap().len();
+        let mut f = std::fs::OpenOptions::new().append(true).open(&toc_path).unwrap();
+        std::io::Write::write_all(&mut f, &[40, 0, 0, 0, 0, 0, 0, 0, 1, 2]).unwrap();
+        ts.open(100);
+        assert_eq!(toc_size, std::fs::metadata(&toc_path).unwrap().len());
+        assert!(ts.kv().orphan_report().is_empty());
+        assert_eq!(tables, count_tables(&ts.directory));
+        verify_basic_kv(&mut ts);
+    }
+
+    #[test]
+    fn quarantine_orphans() {
+        let mut ts = TestStore::create(100);
+        write_basic_kv(&mut ts);
+        ts.kv().flush().unwrap();
+        assert!(ts.close().is_some());
+        let options = Options{quarantine_orphans: true, .. Options::default()};
+        for _ in 0..2 {
+            std::fs::write(format!("{}/1000.tab", ts.directory), b("bogus")).unwrap();
+            ts.store = Some(Store::open_with_options(&ts.directory, 100, options.clone()).unwrap());
+            assert!(ts.kv().orphan_report().removed_tables.is_empty());
+            assert_eq!(1, ts.kv().orphan_report().quarantined_tables.len());
+            assert!(ts.close().is_some());
+        }
+        assert!(std::path::Path::new(&format!("{}/lost+found/1000.tab", ts.directory)).exists());
+        assert!(std::path::Path::new(&format!("{}/lost+found/1000.tab.1", ts.directory)).exists());
+        ts.open(100);
+        assert!(ts.kv().orphan_report().is_empty());
+        verify_basic_kv(&mut ts);
+    }
+
+    #[test]
+    fn locking() {
+        let mut ts = TestStore::create(100);
+        match Store::open(&ts.directory, 100) {
+            Err(ref e) if e.kind() == ErrorKind::Locked => (),
+            Err(e) => panic!("wrong error: {}", e),
+            Ok(_) => panic!("opened a locked store"),
+        }
+        assert!(ts.close().is_some());
+        ts.open(100);
+    }
+
+    #[test]
+    fn error_kinds() {
+        let dir: String = random_testdir();
+        assert_eq!(ErrorKind::NotFound, Store::open(&dir, 100).err().unwrap().kind());
+
+        let mut ts = TestStore::create(100);
+        assert!(ts.close().is_some());
+        let toc_path = format!("{}/toc", ts.directory);
+        flip_byte(&toc_path, 5);
+        match Store::open(&ts.directory, 100) {
+            Err(Error::VersionError{found, expected}) => {
+                assert_eq!((0x1003, 3), (found, expected));
+            },
+            Err(e) => panic!("wrong error: {}", e),
+            Ok(_) => panic!("opened a toc with the wrong version"),
+        }
+        flip_byte(&toc_path, 0);
+        assert_eq!(ErrorKind::Corruption, Store::open(&ts.directory, 100).err().unwrap().kind());
+        flip_byte(&toc_path, 0);
+        flip_byte(&toc_path, 5);
+        ts.open(100);
+    }
+
+    fn flip_byte(path: &str, offset: u64) {
+        let mut data: Vec<u8> = std::fs::read(path).unwrap();
+        data[offset as usize] ^= 0x10;
+        std::fs::write(path, &data).unwrap();
+    }
+
+    #[test]
+    fn corruption() {
+        let mut ts = TestStore::create(100000);
+        write_basic_kv(&mut ts);
+        ts.kv().flush().unwrap();
+        let ti: TableInfo = ts.kv().toc.table_infos.values().next().unwrap().clone();
+        assert_eq!(1, ts.kv().toc.table_infos.len());
+        let path: String = table_filepath(&ts.directory, ti.id);
+        // The index block's offset is in the footer, which is the last 52 bytes.
+        let data: Vec<u8> = std::fs::read(&path).unwrap();
+        let mut pos: usize = data.len() - 52 + 16;
+        let index_offset: u64 = encoding::decode_u64(&data, &mut pos).unwrap();
+
+        for &offset in [0, index_offset].iter() {
+            // Reopen, so that no cached blocks hide the damage.
+            assert!(ts.close().is_some());
+            ts.open(100000);
+            flip_byte(&path, offset);
+            match ts.kv().get(b("0")) {
+                Err(Error::CorruptionError{table_id, offset: bad_offset}) => {
+                    assert_eq!(ti.id.0, table_id);
+                    assert_eq!(offset, bad_offset);
+                },
+                Err(e) => panic!("wrong error: {}", e),
+                Ok(_) => panic!("corruption not detected"),
+            }
+            let interval = Interval::<Buf>{lower: Bound::Unbounded, upper: Bound::Unbounded};
+            assert!(ts.kv().range(&interval).is_err());
+            flip_byte(&path, offset);
+        }
+
+        // A footer whose filter size runs past the end of the file, as far as a u64 goes.
+        assert!(ts.close().is_some());
+        let mut bad: Vec<u8> = data.clone();
+        let size_pos: usize = data.len() - 52 + 8;
+        bad[size_pos..size_pos + 8].copy_from_slice(&[0xff; 8]);
+        std::fs::write(&path, &bad).unwrap();
+        assert_eq!(ErrorKind::Corruption, Store::open(&ts.directory, 100000).err().unwrap().kind());
+        std::fs::write(&path, &data).unwrap();
+        ts.open(100000);
+
+        // The table format version is just before the 4 magic bytes at the end.
+        flip_byte(&path, ti.file_size - 8);
+        assert_eq!(ErrorKind::VersionMismatch, ts.kv().get(b("0")).err().unwrap().kind());
+        flip_byte(&path, ti.file_size - 8);
+        verify_basic_kv(&mut ts);
+    }
+
+    #[test]
+    fn bloom_filters() {
+        for &bits_per_key in [10, 0].iter() {
+            let mut ts = TestStore::create(100000);
+            assert!(ts.close().is_some());
+            let options = Options{bloom_bits_per_key: bits_per_key, .. Options::default()};
+            ts.store = Some(Store::open_with_options(&ts.directory, 100000, options.clone()).unwrap());
+            write_basic_kv(&mut ts);
+            ts.kv().flush().unwrap();
+            // Make sure the filter gets loaded from disk.
+            assert!(ts.close().is_some());
+            ts.store = Some(Store::open_with_options(&ts.directory, 100000, options).unwrap());
+
+            // Hide the table file, so that anything that touches it fails.
+            let ti: TableInfo = ts.kv().toc.table_infos.values().next().unwrap().clone();
+            let path: String = table_filepath(&ts.directory, ti.id);
+            std::fs::rename(&path, format!("{}.hidden", path)).unwrap();
+            assert_eq!(ErrorKind::NotFound, ts.kv().get(b("0")).err().unwrap().kind());
+            if bits_per_key > 0 {
+                // "50x" is within the table's key range, so only the filter keeps us out.
+                assert_eq!(None, ts.kv().get(b("50x")).unwrap());
+                assert!(!ts.kv().exists(b("50x")).unwrap());
+            } else {
+                assert!(ts.kv().get(b("50x")).is_err());
+            }
+            std::fs::rename(format!("{}.hidden", path), &path).unwrap();
+            verify_basic_kv(&mut ts);
+        }
+    }
+
     fn big_key(num: u64) -> Buf { format!("{:08}", num).as_bytes().to_vec() }
     fn big_value(num: u64) -> Buf { format!("value-{}", num).as_bytes().to_vec() }
 
@@ -784,6 +1447,333 @@ mod tests {
         verify_big_kv(&mut ts);
     }
 
+    #[test]
+    fn toc_rewrite() {
+        let mut ts = TestStore::create(100);
+        write_big_kv(&mut ts, 1000);
+        let toc_size: u64 = std::fs::metadata(format!("{}/toc", ts.directory)).unwrap().len();
+        assert_eq!(toc_size, ts.kv().toc.file_size);
+        // Without rewrites, the toc would be hundreds of KB by now.
+        assert!(toc_size < 4 * ts.kv().toc.live_size + 64 * 1024);
+        let next_table_id: u64 = ts.kv().toc.next_table_id;
+        assert!(ts.close().is_some());
+        ts.open(100);
+        // Table ids don't get reused, even for tables only the old toc entries mentioned.
+        assert!(ts.kv().toc.next_table_id >= next_table_id);
+        verify_big_kv(&mut ts);
+    }
+
+    #[test]
+    fn level_keys() {
+        let mut ts = TestStore::create(100);
+        write_big_kv(&mut ts, 1000);
+        ts.kv().flush().unwrap();
+        let kv = ts.kv();
+        let toc: &Toc = &kv.toc;
+        assert!(toc.level_infos.get(&1).is_some_and(|ids| ids.len() > 1));
+        for (&level, table_ids) in toc.level_infos.iter() {
+            if level == 0 {
+                assert!(!toc.level_keys.contains_key(&0));
+                continue;
+            }
+            let keyed: Vec<TableId> = toc.level_keys[&level].values().cloned().collect();
+            let mut ids: Vec<TableId> = keyed.clone();
+            ids.sort();
+            assert_eq!(table_ids.iter().cloned().collect::<Vec<_>>(), ids);
+            for pair in keyed.windows(2) {
+                assert!(toc.table_infos[&pair[0]].biggest_key < toc.table_infos[&pair[1]].smallest_key);
+            }
+
+            for i in 0..1001 {
+                let key: Buf = big_key(i);
+                let expected: Vec<TableId> = keyed.iter().cloned().filter(|id| {
+                    let ti: &TableInfo = &toc.table_infos[id];
+                    key >= ti.smallest_key && key <= ti.biggest_key
+                }).collect();
+                assert_eq!(expected, table_for_key(toc, level, &key).map(|ti| ti.id).into_iter().collect::<Vec<_>>());
+            }
+            for &(low, high) in [(0, 1000), (100, 200), (333, 334), (500, 500), (999, 2000)].iter() {
+                let interval = Interval::<Buf>{
+                    lower: Bound::Excluded(big_key(low)),
+                    upper: Bound::Included(big_key(high)),
+                };
+                let expected: Vec<TableId> = keyed.iter().cloned().filter(|id| {
+                    let ti: &TableInfo = &toc.table_infos[id];
+                    above_lower_bound(&ti.biggest_key, &interval.lower) && below_upper_bound(&ti.smallest_key, &interval.upper)
+                }).collect();
+                let found: Vec<TableId> = tables_in_interval(toc, level, &interval).iter().map(|ti| ti.id).collect();
+                assert_eq!(expected, found);
+            }
+        }
+        verify_big_kv(&mut ts);
+    }
+
+    #[test]
+    fn level_sizes() {
+        let mut ts = TestStore::create(100);
+        assert!(ts.close().is_some());
+        let options = Options{level_base_size: 4096, level_size_multiplier: 3, .. Options::default()};
+        ts.store = Some(Store::open_with_options(&ts.directory, 100, options.clone()).unwrap());
+        write_big_kv(&mut ts, 1000);
+        // Compactions run in the background, so the levels are only sure to be in shape once
+        // flush waits for them.
+        ts.kv().flush().unwrap();
+        {
+            let kv = ts.kv();
+            let levels: Vec<LevelNumber> = kv.toc.level_infos.iter()
+                .filter(|&(_, ids)| !ids.is_empty()).map(|(&level, _)| level).collect();
+            assert!(levels.len() > 2);
+            for &level in levels.iter() {
+                let size: u64 = kv.toc.level_infos[&level].iter().map(|id| kv.toc.table_infos[id].file_size).sum();
+                assert_eq!(size, kv.toc.level_sizes[&level]);
+                assert!(kv.level_score(level) <= 1.0);
+            }
+            assert!(kv.toc.level_sizes[&1] <= 4096);
+            assert!(kv.toc.level_sizes[&2] <= 3 * 4096);
+        }
+        verify_big_kv(&mut ts);
+
+        assert!(ts.close().is_some());
+        let bad = Options{level_size_multiplier: 1, .. options};
+        let err = Store::open_with_options(&ts.directory, 100, bad).err().unwrap();
+        assert_eq!(ErrorKind::InvalidArgument, err.kind());
+        ts.open(100);
+    }
+
+    #[test]
+    fn background_compaction() {
+        let mut ts = TestStore::create(100);
+        assert!(ts.close().is_some());
+        let options = Options{max_immutable_memstores: 1, level_zero_stop_tables: 6, .. Options::default()};
+        ts.store = Some(Store::open_with_options(&ts.directory, 100, options).unwrap());
+        {
+            let kv = ts.kv();
+            for i in 0..1000 {
+                kv.put(&big_key(i), &big_value(i)).unwrap();
+                // Writes stall rather than let these grow without bound.
+                assert!(kv.immutables.len() <= 1);
+                assert!(kv.level_zero_tables() <= 6 + 1);
+                if i % 10 == 0 {
+                    assert_eq!(Some(big_value(i / 2)), kv.get(&big_key(i / 2)).unwrap());
+                }
+            }
+            for j in 0..500 {
+                assert!(kv.remove(&big_key(j * 2 + 1)).unwrap());
+            }
+        }
+        // Reads see the memstores being flushed and the tables being compacted.
+        verify_big_kv(&mut ts);
+
+        ts.kv().flush().unwrap();
+        assert!(ts.kv().immutables.is_empty());
+        assert!(!ts.kv().compactor.busy());
+        let logs: usize = std::fs::read_dir(&ts.directory).unwrap()
+            .filter(|ent| ent.as_ref().unwrap().file_name().to_str().unwrap().ends_with(".log")).count();
+        assert_eq!(1, logs);
+        assert!(ts.close().is_some());
+        ts.open(100);
+        assert!(ts.kv().orphan_report().is_empty());
+        verify_big_kv(&mut ts);
+    }
+
+    #[test]
+    fn background_error() {
+        let mut ts = TestStore::create(100);
+        // Flushing the first memstore fails, since a directory is in the way of its table.
+        let table_id = TableId(ts.kv().toc.next_table_id);
+        let blocker: String = table_tmp_filepath(&ts.directory, table_id);
+        std::fs::create_dir(&blocker).unwrap();
+        let mut failed: Option<u64> = None;
+        for i in 0..1000 {
+            if ts.kv().put(&big_key(i), &big_value(i)).is_err() {
+                failed = Some(i);
+                break;
+            }
+        }
+        let failed: u64 = failed.expect("flush failure not reported");
+        assert!(ts.kv().background_error().is_some());
+        assert!(ts.kv().put(b("x"), b("y")).is_err());
+        assert!(ts.kv().flush().is_err());
+        assert!(ts.close().is_some());
+
+        // The writes that failed weren't logged.
+        std::fs::remove_dir(&blocker).unwrap();
+        ts.open(100);
+        for i in 0..failed {
+            assert_eq!(Some(big_value(i)), ts.kv().get(&big_key(i)).unwrap());
+        }
+        assert_eq!(None, ts.kv().get(&big_key(failed)).unwrap());
+        assert_eq!(None, ts.kv().get(b("x")).unwrap());
+        assert!(ts.kv().background_error().is_none());
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn shared_store() {
+        assert_send_sync::<Store>();
+        assert_send_sync::<SharedStore>();
+        let mut ts = TestStore::create(100);
+        let store = SharedStore::new(ts.store.take().unwrap());
+        let mut threads = Vec::new();
+        for t in 0..4 {
+            let writer = store.clone();
+            threads.push(std::thread::spawn(move || {
+                for i in t * 250..(t + 1) * 250 {
+                    writer.put(&big_key(i), &big_value(i)).unwrap();
+                }
+            }));
+            let reader = store.clone();
+            threads.push(std::thread::spawn(move || {
+                for i in 0..1000 {
+                    if let Some(value) = reader.get(&big_key(i)).unwrap() {
+                        assert_eq!(big_value(i), value);
+                    }
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        for i in 0..1000 {
+            assert_eq!(Some(big_value(i)), store.get(&big_key(i)).unwrap());
+        }
+        let kv = store.read();
+        let mut it: StoreIter = kv.range(&Interval{lower: Bound::Unbounded, upper: Bound::Unbounded}).unwrap();
+        let mut count = 0;
+        while kv.next(&mut it).unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(1000, count);
+    }
+
+    #[test]
+    fn shared_store_stalls() {
+        let mut ts = TestStore::create(100);
+        assert!(ts.close().is_some());
+        let options = Options{max_immutable_memstores: 1, level_zero_stop_tables: 6, .. Options::default()};
+        let store = SharedStore::open_with_options(&ts.directory, 100, options).unwrap();
+        let mut threads = Vec::new();
+        for t in 0..4 {
+            let writer = store.clone();
+            threads.push(std::thread::spawn(move || {
+                for i in t * 100..(t + 1) * 100 {
+                    writer.put(&big_key(i), &big_value(i)).unwrap();
+                    // Writers wait for room without the lock, but still never overfill the queues.
+                    let kv = writer.read();
+                    assert!(kv.immutables.len() <= 1);
+                    assert!(kv.level_zero_tables() <= 6 + 1);
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        store.flush().unwrap();
+        {
+            let kv = store.read();
+            assert!(kv.immutables.is_empty());
+            assert!(!kv.compactor.busy());
+        }
+        for i in 0..400 {
+            assert_eq!(Some(big_value(i)), store.get(&big_key(i)).unwrap());
+        }
+    }
+
+    #[test]
+    fn transactions() {
+        let mut ts = TestStore::create(100);
+        let store = SharedStore::new(ts.store.take().unwrap());
+        store.put(b("stock"), b("5")).unwrap();
+
+        // Reads see the store as of begin, plus the transaction's own writes.
+        let mut txn: Transaction = store.begin();
+        store.put(b("stock"), b("4")).unwrap();
+        assert_eq!(Some(b("5").to_vec()), txn.get(b("stock")).unwrap());
+        txn.put(b("note"), b("hi"));
+        assert_eq!(Some(b("hi").to_vec()), txn.get(b("note")).unwrap());
+        txn.remove(b("note"));
+        assert_eq!(None, txn.get(b("note")).unwrap());
+        // "stock" changed since txn began, so committing fails and writes nothing.
+        txn.put(b("stock"), b("6"));
+        let err = txn.commit().err().unwrap();
+        assert_eq!(ErrorKind::Conflict, err.kind());
+        assert_eq!(Some(b("4").to_vec()), store.get(b("stock")).unwrap());
+
+        // Reading a missing key conflicts with its insertion, while writes to keys the
+        // transaction didn't read don't conflict.
+        let mut txn: Transaction = store.begin();
+        assert_eq!(None, txn.get(b("missing")).unwrap());
+        txn.put(b("other"), b("1"));
+        store.put(b("other"), b("2")).unwrap();
+        let mut txn2: Transaction = store.begin();
+        assert_eq!(None, txn2.get(b("missing")).unwrap());
+        store.put(b("missing"), b("now")).unwrap();
+        assert_eq!(ErrorKind::Conflict, txn.commit().err().unwrap().kind());
+        txn2.put(b("stock"), b("3"));
+        assert_eq!(ErrorKind::Conflict, txn2.commit().err().unwrap().kind());
+        let mut txn: Transaction = store.begin();
+        assert_eq!(Some(b("4").to_vec()), txn.get(b("stock")).unwrap());
+        store.put(b("other"), b("3")).unwrap();
+        txn.put(b("stock"), b("3"));
+        txn.commit().unwrap();
+        assert_eq!(Some(b("3").to_vec()), store.get(b("stock")).unwrap());
+
+        // Counters incremented from several threads, retrying on conflicts, lose no updates.
+        let mut threads = Vec::new();
+        for _ in 0..4 {
+            let store = store.clone();
+            threads.push(std::thread::spawn(move || {
+                for i in 0..100 {
+                    let key: Buf = big_key(i % 5);
+                    loop {
+                        let mut txn: Transaction = store.begin();
+                        let count: u64 = txn.get(&key).unwrap()
+                            .map_or(0, |v| String::from_utf8(v).unwrap().parse().unwrap());
+                        txn.put(&key, format!("{}", count + 1).as_bytes());
+                        match txn.commit() {
+                            Ok(()) => break,
+                            Err(ref e) if e.kind() == ErrorKind::Conflict => continue,
+                            Err(e) => panic!("commit failed: {}", e),
+                        }
+                    }
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        for i in 0..5 {
+            assert_eq!(Some(b("80").to_vec()), store.get(&big_key(i)).unwrap());
+        }
+    }
+
+    #[test]
+    fn transaction_background_error() {
+        let mut ts = TestStore::create(100);
+        let table_id = TableId(ts.kv().toc.next_table_id);
+        let blocker: String = table_tmp_filepath(&ts.directory, table_id);
+        std::fs::create_dir(&blocker).unwrap();
+        let store = SharedStore::new(ts.store.take().unwrap());
+        let mut i = 0;
+        while store.put(&big_key(i), &big_value(i)).is_ok() {
+            i += 1;
+            assert!(i < 1000, "flush failure not reported");
+        }
+
+        // The commit fails before its batch gets logged.
+        let mut txn: Transaction = store.begin();
+        txn.put(b("x"), b("y"));
+        assert!(txn.commit().is_err());
+        drop(store);
+        std::fs::remove_dir(&blocker).unwrap();
+        ts.open(100);
+        assert_eq!(None, ts.kv().get(b("x")).unwrap());
+        assert_eq!(Some(big_value(0)), ts.kv().get(&big_key(0)).unwrap());
+    }
+
     #[test]
     fn big_many_disk() {
         let mut ts = TestStore::create(100);
@@ -806,6 +1796,205 @@ mod tests {
         verify_big_kv(&mut ts);
     }
 
+    #[test]
+    fn index_lookups() {
+        // One table with several data blocks.
+        let mut ts = TestStore::create(2000000);
+        write_big_kv(&mut ts, 1000);
+        ts.kv().flush().unwrap();
+        assert_eq!(1, ts.kv().toc.table_infos.len());
+        let kv = ts.kv();
+        for i in 0..1000 {
+            let expected = if i % 2 == 0 { Some(big_value(i)) } else { None };
+            assert_eq!(expected, kv.get(&big_key(i)).unwrap());
+        }
+        assert_eq!(None, kv.get(b("")).unwrap());
+        assert_eq!(None, kv.get(b("zzz")).unwrap());
+        for &(low, high) in [(16, 31), (15, 32), (2, 998), (500, 500), (17, 17), (2, 4)].iter() {
+            verify_big_kv_range(kv, low, high);
+        }
+        // Excluded bounds, on keys that are present, stepping over block boundaries.
+        for &(low, high) in [(0, 998), (100, 700), (2, 6), (400, 402)].iter() {
+            let interval = Interval::<Buf>{
+                lower: Bound::Excluded(big_key(low)),
+                upper: Bound::Excluded(big_key(high)),
+            };
+            let expected: Vec<Buf> = (low / 2 + 1..high / 2).map(|j| big_key(j * 2)).collect();
+            let mut it: StoreIter = kv.range(&interval).unwrap();
+            let mut keys = Vec::<Buf>::new();
+            while let Some((k, _)) = kv.next(&mut it).unwrap() {
+                keys.push(k);
+            }
+            assert_eq!(expected, keys);
+            let mut it: StoreIter = kv.range_descending(&interval).unwrap();
+            keys.clear();
+            while let Some((k, _)) = kv.next(&mut it).unwrap() {
+                keys.push(k);
+            }
+            keys.reverse();
+            assert_eq!(expected, keys);
+        }
+    }
+
+    #[test]
+    fn block_cache() {
+        for &capacity in [1 << 20, 0].iter() {
+            let mut ts = TestStore::create(2000000);
+            assert!(ts.close().is_some());
+            let options = Options{block_cache_capacity: capacity, .. Options::default()};
+            ts.store = Some(Store::open_with_options(&ts.directory, 2000000, options).unwrap());
+            write_big_kv(&mut ts, 1000);
+            ts.kv().flush().unwrap();
+            let kv = ts.kv();
+            assert_eq!(CacheStats::default(), kv.block_cache_stats());
+
+            // The first get misses on the data block, the second hits it.  (The index block
+            // stays with the open table.)
+            assert_eq!(Some(big_value(500)), kv.get(&big_key(500)).unwrap());
+            assert_eq!(CacheStats{hits: 0, misses: 1}, kv.block_cache_stats());
+            assert_eq!(Some(big_value(500)), kv.get(&big_key(500)).unwrap());
+            let expected_hits: u64 = if capacity == 0 { 0 } else { 1 };
+            assert_eq!(expected_hits, kv.block_cache_stats().hits);
+            verify_big_kv_range(kv, 2, 998);
+        }
+    }
+
+    #[test]
+    fn table_cache() {
+        for &capacity in [3, 0].iter() {
+            let mut ts = TestStore::create(100);
+            assert!(ts.close().is_some());
+            let options = Options{table_cache_capacity: capacity, .. Options::default()};
+            ts.store = Some(Store::open_with_options(&ts.directory, 100, options).unwrap());
+            // Lots of tables, with relevels deleting some along the way.
+            write_big_kv(&mut ts, 1000);
+            assert!(ts.kv().toc.table_infos.len() > capacity);
+            verify_big_kv_range(ts.kv(), 2, 998);
+            for i in 0..1000 {
+                let expected = if i % 2 == 0 { Some(big_value(i)) } else { None };
+                assert_eq!(expected, ts.kv().get(&big_key(i)).unwrap());
+            }
+            assert_eq!(capacity, ts.kv().table_cache.lock().unwrap().len());
+        }
+    }
+
+    #[test]
+    fn compression() {
+        let mut sizes = Vec::<u64>::new();
+        for &compression in [Compression::None, Compression::Lz].iter() {
+            let mut ts = TestStore::create(1000000);
+            assert!(ts.close().is_some());
+            let options = Options{compression: compression, .. Options::default()};
+            ts.store = Some(Store::open_with_options(&ts.directory, 1000000, options).unwrap());
+            let json = |i: u64| format!("{{\"id\": {}, \"kind\": \"entity\", \"tags\": [\"a\", \"b\"]}}", i).into_bytes();
+            for i in 0..1000 {
+                ts.kv().put(&big_key(i), &json(i)).unwrap();
+            }
+            ts.kv().flush().unwrap();
+            sizes.push(ts.kv().toc.table_infos.values().map(|ti| ti.file_size).sum());
+
+            // Reopen with the other codec -- tables keep the one they were written with.
+            assert!(ts.close().is_some());
+            let other = if compression == Compression::Lz { Compression::None } else { Compression::Lz };
+            let options = Options{compression: other, .. Options::default()};
+            ts.store = Some(Store::open_with_options(&ts.directory, 1000000, options).unwrap());
+            for i in 0..1000 {
+                assert_eq!(Some(json(i)), ts.kv().get(&big_key(i)).unwrap());
+            }
+        }
+        assert!(sizes[1] < sizes[0] / 2);
+    }
+
+    #[test]
+    fn mmap_tables() {
+        for &compression in [Compression::None, Compression::Lz].iter() {
+            let mut ts = TestStore::create(1000);
+            assert!(ts.close().is_some());
+            let options = Options{mmap_tables: true, compression: compression, .. Options::default()};
+            ts.store = Some(Store::open_with_options(&ts.directory, 1000, options.clone()).unwrap());
+            write_big_kv(&mut ts, 1000);
+            ts.kv().flush().unwrap();
+            verify_big_kv_range(ts.kv(), 2, 998);
+            for i in 0..1000 {
+                let expected = if i % 2 == 0 { Some(big_value(i)) } else { None };
+                assert_eq!(expected, ts.kv().get(&big_key(i)).unwrap());
+            }
+            // Uncompressed mapped blocks don't go through the block cache.
+            assert_eq!(compression == Compression::None, CacheStats::default() == ts.kv().block_cache_stats());
+
+            // Checksums still get checked.
+            let ti: TableInfo = ts.kv().toc.table_infos.values().next().unwrap().clone();
+            assert!(ts.close().is_some());
+            flip_byte(&table_filepath(&ts.directory, ti.id), 0);
+            ts.store = Some(Store::open_with_options(&ts.directory, 1000, options).unwrap());
+            // A full scan touches every table.  (A get might find its key in a newer table first.)
+            let kv = ts.kv();
+            let interval = Interval::<Buf>{lower: Bound::Unbounded, upper: Bound::Unbounded};
+            let mut result: Result<Option<(Buf, Buf)>>;
+            match kv.range(&interval) {
+                Err(e) => result = Err(e),
+                Ok(mut it) => loop {
+                    result = kv.next(&mut it);
+                    if let Ok(Some(_)) = result {
+                        continue;
+                    }
+                    break;
+                },
+            }
+            match result {
+                Err(Error::CorruptionError{table_id, offset}) => assert_eq!((ti.id.0, 0), (table_id, offset)),
+                Err(e) => panic!("wrong error: {}", e),
+                Ok(_) => panic!("corruption not detected"),
+            }
+        }
+    }
+
+    #[test]
+    fn sync_modes() {
+        for &mode in [SyncMode::NoSync, SyncMode::GroupCommit(10), SyncMode::SyncPerWrite].iter() {
+            let mut ts = TestStore::create(100000);
+            assert!(ts.close().is_some());
+            let options = Options{sync_mode: mode, .. Options::default()};
+            ts.store = Some(Store::open_with_options(&ts.directory, 100000, options).unwrap());
+            write_basic_kv(&mut ts);
+            {
+                let kv = ts.kv();
+                let opts = WriteOptions{sync: true};
+                kv.put_opt(b("zz"), b("value-zz"), &opts).unwrap();
+                assert_eq!(0, kv.log.unsynced);
+                assert!(!kv.insert_opt(b("zz"), b("value-zz2"), &opts).unwrap());
+                assert!(kv.replace_opt(b("zz"), b("value-zz3"), &WriteOptions::default()).unwrap());
+                assert!(kv.remove_opt(b("zz"), &opts).unwrap());
+                assert_eq!(0, kv.log.unsynced);
+
+                // With the log just synced, nine more writes stay unsynced in every mode but
+                // SyncPerWrite, and the tenth syncs them all under GroupCommit(10).
+                for i in 0..9 {
+                    kv.put(format!("y{}", i).as_bytes(), b("value-y")).unwrap();
+                }
+                assert_eq!(if mode == SyncMode::SyncPerWrite { 0 } else { 9 }, kv.log.unsynced);
+                kv.put(b("y9"), b("value-y")).unwrap();
+                let expected: usize = match mode {
+                    SyncMode::NoSync => 10,
+                    SyncMode::GroupCommit(_) | SyncMode::SyncPerWrite => 0,
+                };
+                assert_eq!(expected, kv.log.unsynced);
+                // NoSync never syncs on its own, and GroupCommit starts counting again.
+                kv.put(b("y10"), b("value-y")).unwrap();
+                let expected: usize = match mode {
+                    SyncMode::NoSync => 11,
+                    SyncMode::GroupCommit(_) => 1,
+                    SyncMode::SyncPerWrite => 0,
+                };
+                assert_eq!(expected, kv.log.unsynced);
+            }
+            assert!(ts.close().is_some());
+            ts.open(100000);
+            verify_basic_kv(&mut ts);
+            assert_eq!(None, ts.kv().get(b("zz")).unwrap());
+        }
+    }
+
     #[test]
     fn sync() {
         // Tests that sync generally works.
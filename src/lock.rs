@@ -0,0 +1,29 @@
+use error::*;
+
+use libc;
+use std;
+use std::os::unix::io::AsRawFd;
+
+// An exclusive flock on the store directory's LOCK file.  It's released when the file gets
+// closed, i.e. when this is dropped (or the process dies).
+pub struct DirLock {
+    _file: std::fs::File,
+}
+
+fn lock_filepath(dir: &str) -> String {
+    return format!("{}/LOCK", dir);
+}
+
+pub fn lock_directory(dir: &str) -> Result<DirLock> {
+    let path: String = lock_filepath(dir);
+    let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).at_path(&path)?;
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret != 0 {
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Err(Error::LockedError(path));
+        }
+        return Err(Error::IoError(e, Some(path)));
+    }
+    return Ok(DirLock{_file: file});
+}
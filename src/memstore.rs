@@ -7,54 +7,64 @@ use std::collections::*;
 use std::collections::btree_map::*;
 
 pub struct MemStore {
-    pub entries: BTreeMap<Buf, Mutation>,
+    pub entries: BTreeMap<Buf, Versions>,
     pub mem_usage: usize,
 }
 
-fn entries_range2<'a>(entries: &'a BTreeMap<Buf, Mutation>, lower: Bound<&[u8]>, upper: Bound<&[u8]>
-) -> Range<'a, Buf, Mutation> {
+fn entries_range2<'a>(entries: &'a BTreeMap<Buf, Versions>, lower: Bound<&[u8]>, upper: Bound<&[u8]>
+) -> Range<'a, Buf, Versions> {
     entries.range::<[u8], (Bound<&[u8]>, Bound<&[u8]>)>((lower, upper))
 }
 
-fn entries_range<'a>(entries: &'a BTreeMap<Buf, Mutation>, interval: &Interval<Buf>
-) -> Range<'a, Buf, Mutation> {
+fn entries_range<'a>(entries: &'a BTreeMap<Buf, Versions>, interval: &Interval<Buf>
+) -> Range<'a, Buf, Versions> {
     entries_range2(entries, ref_bound(&interval.lower), ref_bound(&interval.upper))
 }
 
+// Returns the key of the entry, if the reader at the snapshot can see any version of it.
+fn visible_key<'a>(entry: (&'a Buf, &'a Versions), snapshot: SeqNum) -> Option<&'a [u8]> {
+    return visible_version(entry.1, snapshot).map(|_| entry.0 as &[u8]);
+}
+
 impl MemStore {
-    pub fn apply(&mut self, key: Buf, val: Mutation) {
+    // `seq` can't be older than any mutation already applied.  Versions that none of the
+    // live `snapshots` (in ascending order) can read get dropped.
+    pub fn apply(&mut self, key: Buf, seq: SeqNum, val: Mutation, snapshots: &[SeqNum]) {
         let k_usage: usize = disk::approx_key_usage(&key);
-        let old_usage: usize;
-        if let Some(old_value) = self.entries.get(&key) {
-            old_usage = k_usage + disk::approx_value_usage(&old_value);
+        let versions: &mut Versions = self.entries.entry(key).or_default();
+        let old_usage: usize = if versions.is_empty() { 0 } else { k_usage + disk::approx_versions_usage(versions) };
+
+        if versions.first().is_some_and(|&(newest, _)| newest == seq) {
+            // A batch's later mutation to the key supercedes its earlier one.
+            versions[0].1 = val;
         } else {
-            old_usage = 0;
+            versions.insert(0, (seq, val));
         }
-
-        let new_usage: usize = k_usage + disk::approx_value_usage(&val);
+        retain_readable(versions, snapshots);
+        let new_usage: usize = k_usage + disk::approx_versions_usage(versions);
 
         // Wrapping ops to avoid intermediate overflow -- only a concern if this
         // one mutation is huge.
         self.mem_usage = self.mem_usage.wrapping_add(new_usage).wrapping_sub(old_usage);
-        self.entries.insert(key, val);
     }
 
-    pub fn lookup(&self, key: &[u8]) -> Option<&Mutation> {
-        return self.entries.get(key);
+    // The newest version of the key that a reader at the snapshot can see.
+    pub fn lookup(&self, key: &[u8], snapshot: SeqNum) -> Option<&(SeqNum, Mutation)> {
+        return visible_version(self.entries.get(key)?, snapshot);
     }
 
-    pub fn first_in_range(&self, interval: &Interval<Buf>) -> Option<&[u8]> {
-        let mut range: Range<Buf, Mutation> = entries_range(&self.entries, interval);
-        return range.next().map(|(key, _)| key as &[u8]);
+    pub fn first_in_range(&self, interval: &Interval<Buf>, snapshot: SeqNum) -> Option<&[u8]> {
+        let mut range: Range<Buf, Versions> = entries_range(&self.entries, interval);
+        return range.find_map(|entry| visible_key(entry, snapshot));
     }
 
-    pub fn last_in_range(&self, interval: &Interval<Buf>) -> Option<&[u8]> {
-        let mut range: Range<Buf, Mutation> = entries_range(&self.entries, interval);
-        return range.next_back().map(|(key, _)| key as &[u8]);
+    pub fn last_in_range(&self, interval: &Interval<Buf>, snapshot: SeqNum) -> Option<&[u8]> {
+        let range: Range<Buf, Versions> = entries_range(&self.entries, interval);
+        return range.rev().find_map(|entry| visible_key(entry, snapshot));
     }
 
     pub fn new() -> MemStore {
-        return MemStore{entries: BTreeMap::<Buf, Mutation>::new(), mem_usage: 0};
+        return MemStore{entries: BTreeMap::<Buf, Versions>::new(), mem_usage: 0};
     }
 }
 
@@ -65,25 +75,37 @@ pub struct MemStoreIterator<'a> {
     current: Option<&'a [u8]>,
     bound: Bound<Buf>,
     direction: Direction,
+    // Keys with no version at or before this don't exist, as far as we're concerned.
+    snapshot: SeqNum,
 }
 
 impl<'a> MemStoreIterator<'a> {
-    pub fn make(ms: &'a MemStore, interval: &Interval<Buf>, direction: Direction) -> MemStoreIterator<'a> {
+    pub fn make(ms: &'a MemStore, interval: &Interval<Buf>, direction: Direction, snapshot: SeqNum
+    ) -> MemStoreIterator<'a> {
         return match direction {
             Direction::Forward => MemStoreIterator{
                 memstore: ms,
-                current: ms.first_in_range(interval),
+                current: ms.first_in_range(interval, snapshot),
                 bound: interval.upper.clone(),
                 direction: direction,
+                snapshot: snapshot,
             },
             Direction::Backward => MemStoreIterator{
                 memstore: ms,
-                current: ms.last_in_range(interval),
+                current: ms.last_in_range(interval, snapshot),
                 bound: interval.lower.clone(),
                 direction: direction,
+                snapshot: snapshot,
             }
         }
     }
+
+    fn current_versions_ref(&self) -> Result<&'a Versions> {
+        if let Some(key) = self.current {
+            return Ok(self.memstore.entries.get(key).expect("invalid MemStoreIterator"));
+        }
+        return invalid_arg_err("current_value called on empty MemStoreIterator");
+    }
 }
 
 impl<'a> MutationIterator for MemStoreIterator<'a> {
@@ -92,36 +114,32 @@ impl<'a> MutationIterator for MemStoreIterator<'a> {
     }
 
     fn current_value(&mut self) -> Result<Mutation> {
-        if let Some(key) = self.current {
-            return Ok(self.memstore.lookup(key).expect("invalid MemStoreIterator").clone());
-        }
-        return mk_err("current_value called on empty MemStoreIterator");
+        let versions: &Versions = self.current_versions_ref()?;
+        return Ok(visible_version(versions, self.snapshot).expect("invalid MemStoreIterator").1.clone());
+    }
+
+    fn current_versions(&mut self) -> Result<Versions> {
+        let versions: &Versions = self.current_versions_ref()?;
+        return Ok(versions.iter().filter(|&&(seq, _)| seq <= self.snapshot).cloned().collect());
     }
 
     fn step(&mut self) -> Result<()> {
-        let current_bound = Bound::Excluded(self.current.or_err("step past end")?);
+        let current_bound = Bound::Excluded(self.current.or_invalid_arg("step past end")?);
+        let snapshot: SeqNum = self.snapshot;
         match self.direction {
             Direction::Forward => {
-                let mut range: Range<Buf, Mutation>
+                let mut range: Range<Buf, Versions>
                     = entries_range2(&self.memstore.entries, current_bound, ref_bound(&self.bound));
-                if let Some((key, _)) = range.next() {
-                    self.current = Some(&key);
-                } else {
-                    self.current = None;
-                }
+                self.current = range.find_map(|entry| visible_key(entry, snapshot));
                 return Ok(());
             }
             Direction::Backward => {
-                let mut range: Range<Buf, Mutation> = self.memstore.entries.range::<[u8], (Bound<&[u8]>, Bound<&[u8]>)>(
+                let range: Range<Buf, Versions> = self.memstore.entries.range::<[u8], (Bound<&[u8]>, Bound<&[u8]>)>(
                     (ref_bound(&self.bound), current_bound)
                 );
-                if let Some((key, _)) = range.next_back() {
-                    self.current = Some(&key);
-                } else {
-                    self.current = None;
-                }
+                self.current = range.rev().find_map(|entry| visible_key(entry, snapshot));
                 return Ok(());
             }
         }
     }
-}
\ No newline at end of file
+}
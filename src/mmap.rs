@@ -0,0 +1,47 @@
+use error::*;
+
+use libc;
+use std;
+use std::os::unix::io::AsRawFd;
+
+// A read-only, shared memory map of a whole file.  It's unmapped when dropped.
+//
+// Table files are never modified once they're renamed into place, so the mapped bytes don't
+// change under us.  (A file shrinking would make accesses fault, so we check its size first.)
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    pub fn map(f: &std::fs::File, path: &str, len: usize) -> Result<Mmap> {
+        if len == 0 || f.metadata().at_path(path)?.len() < len as u64 {
+            return mk_err("file too small to map");
+        }
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, f.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::IoError(std::io::Error::last_os_error(), Some(path.to_string())));
+        }
+        return Ok(Mmap{ptr: ptr, len: len});
+    }
+}
+
+// The mapping is read-only, so any thread can read it, and unmap it at the end.
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        return unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) };
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
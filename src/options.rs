@@ -0,0 +1,92 @@
+use snapshot::Snapshot;
+
+/// When the store fsyncs its log on its own.  Whatever the mode, a write with
+/// `WriteOptions::sync` set is durable once it returns, and so is every write
+/// before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Only fsync the log when asked to.
+    NoSync,
+    /// fsync the log once every `n` writes, covering all `n` with one fsync.
+    GroupCommit(usize),
+    /// fsync the log after every write.
+    SyncPerWrite,
+}
+
+/// How table data blocks get compressed.  Each table records its own codec, so this can
+/// change from one `open` to the next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// A fast LZ77-family codec.
+    Lz,
+}
+
+/// Store-level options, passed to `Store::open_with_options`.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub sync_mode: SyncMode,
+    /// If true, `open` moves table files the toc doesn't reference into `lost+found/`
+    /// instead of deleting them.
+    pub quarantine_orphans: bool,
+    /// Bits per key in each table's bloom filter.  Zero means no filters.
+    pub bloom_bits_per_key: usize,
+    /// Bytes of table blocks to keep cached in memory.  Zero means no cache.
+    pub block_cache_capacity: usize,
+    /// How many table files to keep open, along with their index blocks.  Zero means
+    /// every read opens the file anew.
+    pub table_cache_capacity: usize,
+    /// If true, table files get memory-mapped, and reads borrow straight from the mapping
+    /// instead of copying into the block cache.
+    pub mmap_tables: bool,
+    /// The codec newly written tables use.
+    pub compression: Compression,
+    /// The total size, in bytes, of level 1's tables, past which they get releveled
+    /// into level 2.
+    pub level_base_size: u64,
+    /// How many times bigger each level after level 1 gets than the level before it.
+    pub level_size_multiplier: u64,
+    /// How many full memstores can wait for the compaction thread to flush them before
+    /// writes stall.
+    pub max_immutable_memstores: usize,
+    /// Once level zero has this many tables, writes get delayed a millisecond each, to
+    /// let compaction catch up.
+    pub level_zero_slowdown_tables: usize,
+    /// Once level zero has this many tables, writes that would queue another memstore
+    /// stall until compaction brings it under.
+    pub level_zero_stop_tables: usize,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        return Options{
+            sync_mode: SyncMode::NoSync,
+            quarantine_orphans: false,
+            bloom_bits_per_key: 10,
+            block_cache_capacity: 8 << 20,
+            table_cache_capacity: 500,
+            mmap_tables: false,
+            compression: Compression::Lz,
+            level_base_size: 10 << 20,
+            level_size_multiplier: 10,
+            max_immutable_memstores: 2,
+            level_zero_slowdown_tables: 8,
+            level_zero_stop_tables: 12,
+        };
+    }
+}
+
+/// Per-write options, for `put_opt` and friends.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WriteOptions {
+    /// If true, the write (and all writes before it) are durable once the call returns.
+    pub sync: bool,
+}
+
+/// Per-read options, for `get_opt` and friends.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadOptions<'a> {
+    /// If set, the read sees the store as it was when the snapshot was taken, instead of
+    /// as it is now.
+    pub snapshot: Option<&'a Snapshot>,
+}
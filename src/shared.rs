@@ -0,0 +1,143 @@
+use error::*;
+use options::*;
+use util::*;
+use Snapshot;
+use Store;
+use Transaction;
+use WriteBatch;
+use WriteDelay;
+
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+
+/// A handle to a `Store` that many threads can use at once.  Clones of the handle
+/// share the store.
+///
+/// Any number of threads can read at the same time, while writes take turns, each
+/// with the store to itself.  For what the handle doesn't wrap, such as range
+/// iteration, `read` and `write` give direct access to the store.
+#[derive(Clone)]
+pub struct SharedStore {
+    store: Arc<RwLock<Store>>,
+}
+
+impl SharedStore {
+    pub fn new(mut store: Store) -> SharedStore {
+        store.delays_outside = true;
+        return SharedStore{store: Arc::new(RwLock::new(store))};
+    }
+
+    /// Like `Store::open`.
+    pub fn open(dir: &str, threshold: usize) -> Result<SharedStore> {
+        return Ok(SharedStore::new(Store::open(dir, threshold)?));
+    }
+
+    /// Like `Store::open_with_options`.
+    pub fn open_with_options(dir: &str, threshold: usize, options: Options) -> Result<SharedStore> {
+        return Ok(SharedStore::new(Store::open_with_options(dir, threshold, options)?));
+    }
+
+    /// Locks the store for reading, alongside other readers.  Writers wait until the
+    /// guard is dropped.
+    pub fn read<'a>(&'a self) -> RwLockReadGuard<'a, Store> {
+        return self.store.read().expect("store lock poisoned");
+    }
+
+    /// Locks the store for writing, waiting for readers and other writers.
+    ///
+    /// Writes made through the guard don't wait for background compactions to make room,
+    /// as writes through the handle do.
+    pub fn write<'a>(&'a self) -> RwLockWriteGuard<'a, Store> {
+        return self.store.write().expect("store lock poisoned");
+    }
+
+    // Waits out the next write's stall or slowdown without holding the lock, so that readers,
+    // and the writers installing the compactions we're waiting for, can get at the store.
+    pub(crate) fn make_room(&self) -> Result<()> {
+        loop {
+            let delay: Option<WriteDelay> = self.read().write_delay();
+            match delay {
+                None => return Ok(()),
+                Some(WriteDelay::Slowdown) => {
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                    return Ok(());
+                },
+                Some(WriteDelay::Stall(waiter)) => {
+                    waiter.wait();
+                    self.write().poll_compaction(false)?;
+                },
+            }
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Buf>> {
+        return self.read().get(key);
+    }
+
+    /// Like `Store::get_opt`.
+    pub fn get_opt(&self, key: &[u8], opts: &ReadOptions) -> Result<Option<Buf>> {
+        return self.read().get_opt(key, opts);
+    }
+
+    pub fn exists(&self, key: &[u8]) -> Result<bool> {
+        return self.read().exists(key);
+    }
+
+    /// Like `Store::snapshot`.
+    pub fn snapshot(&self) -> Snapshot {
+        return self.read().snapshot();
+    }
+
+    /// Begins a transaction, which reads the store as it is now.
+    pub fn begin(&self) -> Transaction {
+        return Transaction::begin(self.clone());
+    }
+
+    pub fn put(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.make_room()?;
+        return self.write().put(key, val);
+    }
+
+    /// Like `Store::insert`.  Checking for the key and inserting it happen together.
+    pub fn insert(&self, key: &[u8], val: &[u8]) -> Result<bool> {
+        self.make_room()?;
+        return self.write().insert(key, val);
+    }
+
+    /// Like `Store::replace`.  Checking for the key and replacing it happen together.
+    pub fn replace(&self, key: &[u8], val: &[u8]) -> Result<bool> {
+        self.make_room()?;
+        return self.write().replace(key, val);
+    }
+
+    pub fn remove(&self, key: &[u8]) -> Result<bool> {
+        self.make_room()?;
+        return self.write().remove(key);
+    }
+
+    /// Like `Store::write`.
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.make_room()?;
+        return self.write().write(batch);
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        return self.write().sync();
+    }
+
+    /// Like `Store::flush`, but only holding the lock to start compactions and to record
+    /// what they did.
+    pub fn flush(&self) -> Result<()> {
+        self.write().start_flush()?;
+        loop {
+            let waiter = self.read().compactor.waiter();
+            match waiter {
+                None => return Ok(()),
+                Some(waiter) => waiter.wait(),
+            }
+            self.write().poll_compaction(false)?;
+        }
+    }
+}
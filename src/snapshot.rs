@@ -0,0 +1,54 @@
+use util::*;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// The sequence numbers of a store's live snapshots, each with how many snapshots share it.
+// Shared between the store and its snapshots, which unregister themselves when dropped.
+pub type LiveSnapshots = Arc<Mutex<BTreeMap<SeqNum, usize>>>;
+
+/// The store as of the moment `Store::snapshot` was called.  Reads given the snapshot,
+/// through `ReadOptions`, see none of the writes after that, and compactions keep what
+/// they need for as long as the snapshot lives.
+#[derive(Debug)]
+pub struct Snapshot {
+    seq: SeqNum,
+    live: LiveSnapshots,
+}
+
+impl Snapshot {
+    pub(crate) fn take(live: &LiveSnapshots, seq: SeqNum) -> Snapshot {
+        *live.lock().unwrap().entry(seq).or_insert(0) += 1;
+        return Snapshot{seq: seq, live: live.clone()};
+    }
+
+    // Reads at the snapshot see mutations with this sequence number or older.
+    pub(crate) fn seq(&self) -> SeqNum {
+        return self.seq;
+    }
+
+    // Whether the snapshot was taken of the store with these live snapshots.
+    pub(crate) fn belongs_to(&self, live: &LiveSnapshots) -> bool {
+        return Arc::ptr_eq(&self.live, live);
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut live = self.live.lock().unwrap();
+        let last: bool = {
+            let count: &mut usize = live.get_mut(&self.seq).expect("snapshot not registered");
+            *count -= 1;
+            *count == 0
+        };
+        if last {
+            live.remove(&self.seq);
+        }
+    }
+}
+
+// The live snapshots' sequence numbers, in ascending order.
+pub fn live_snapshots(live: &LiveSnapshots) -> Vec<SeqNum> {
+    return live.lock().unwrap().keys().cloned().collect();
+}
@@ -1,5 +1,4 @@
-extern crate crc;
-
+use disk::sync_directory;
 use encoding::*;
 use error::*;
 use util::*;
@@ -20,42 +19,62 @@ use std::io::Write;
     The version identifying the file format version (so that we don't try to read old
     version file formats).
 
-    Current version: 1
+    Current version: 3  (Version 1 stores held version 1 tables, which we no longer read.)
 
 [entry] format:
 
     [u64][u32][varint]
     L     C    N
 
-    where L is the length of the entry, C is its checksum, and N is the entry.
+    where L is the length of the entry, C is its checksum, and N is the entry:  its
+    removals, its additions, and the next table id at the time it was written.
+
+Once the file gets big enough relative to the table infos it describes, it's rewritten as a
+single entry adding every live table, written to toc.tmp and renamed over toc.
 
 */
 
-const TOC_MAGIC: [u8; 8] = ['N' as u8, 'I' as u8, 'H' as u8, '\0' as u8, 1, 0, 0, 0];
+const TOC_MAGIC: [u8; 8] = ['N' as u8, 'I' as u8, 'H' as u8, '\0' as u8, 3, 0, 0, 0];
+// Version 1 table infos had a keys offset, for version 1 table files.  Version 2 entries
+// didn't record the next table id.
+const TOC_VERSION: u32 = 3;
 
 // NOTE: Make this a newtype.
 pub type LevelNumber = u64;
 
-// NOTE: We should track size of garbage data in TOC and occasionally rewrite from scratch.
+// We rewrite the toc file when it's this many times bigger than a rewritten one would be...
+const TOC_REWRITE_RATIO: u64 = 4;
+// ...and it's at least this big.
+const TOC_REWRITE_MIN_SIZE: u64 = 64 * 1024;
+
 pub struct Toc {
     pub table_infos: fnv::FnvHashMap<TableId, TableInfo>,
-    // NOTE: We'll want levels (besides zero) to be organized by key order.
     pub level_infos: BTreeMap<LevelNumber, BTreeSet<TableId>>,
+    // The tables of each level besides zero, by smallest key.  Those levels' tables don't
+    // overlap, so at most one of them can hold any given key.
+    pub level_keys: BTreeMap<LevelNumber, BTreeMap<Buf, TableId>>,
+    // The total file size of each level's tables.
+    pub level_sizes: BTreeMap<LevelNumber, u64>,
     pub next_table_id: u64,
+    // Size of the toc file.
+    pub file_size: u64,
+    // Total encoded size of the table infos -- roughly what a rewritten toc file would take.
+    pub live_size: u64,
 }
 
 #[derive(Debug)]
 pub struct Entry {
     pub removals: Vec<TableId>,
     pub additions: Vec<TableInfo>,
+    // So that table ids never get reused, even once the tables with the biggest ones are
+    // removed and the toc is rewritten without them.
+    pub next_table_id: u64,
 }
 
 #[derive(Debug, Clone)]
 pub struct TableInfo {
     pub id: TableId,
     pub level: LevelNumber,
-    // Offset of the keys in the table file
-    pub keys_offset: u64,
     pub file_size: u64,
     // The smallest and biggest keys (defining a closed interval) in the file.
     // (The file must always have at least one key.)
@@ -67,23 +86,50 @@ fn toc_filename(dir: &str) -> String {
     return format!("{}/toc", dir);
 }
 
+fn toc_tmp_filename(dir: &str) -> String {
+    return format!("{}/toc.tmp", dir);
+}
+
 pub fn create_toc(dir: &str) -> Result<std::fs::File> {
-    let mut f = std::fs::File::create(toc_filename(dir))?;
-    // Start off with version 1.  (The little-endian u32 value 01 00 00 00.)
+    let path: String = toc_filename(dir);
+    let mut f = std::fs::File::create(&path).at_path(&path)?;
+    // Start off with version 3.  (The little-endian u32 value 03 00 00 00.)
     f.write_all(&TOC_MAGIC)?;
+    f.sync_data()?;
+    sync_directory(dir)?;
     return Ok(f);
 }
 
+fn table_info_size(ti: &TableInfo) -> u64 {
+    let mut v = Vec::<u8>::new();
+    encode_table_info(&mut v, ti);
+    return v.len() as u64;
+}
+
 fn remove_table(toc: &mut Toc, table_id: TableId) {
     let ti: TableInfo = toc.table_infos.remove(&table_id).expect("TOC table removal");
+    toc.live_size -= table_info_size(&ti);
     let v: &mut BTreeSet<TableId> = toc.level_infos.get_mut(&ti.level).expect("TOC table removal level");
     let removed: bool = v.remove(&ti.id);
     assert!(removed);
+    *toc.level_sizes.get_mut(&ti.level).expect("TOC table removal size") -= ti.file_size;
+    if ti.level != 0 {
+        let keys: &mut BTreeMap<Buf, TableId> = toc.level_keys.get_mut(&ti.level).expect("TOC table removal keys");
+        let removed: Option<TableId> = keys.remove(&ti.smallest_key);
+        assert!(removed == Some(ti.id));
+    }
 }
 
 fn add_table(toc: &mut Toc, table_info: TableInfo) {
     let table_id = table_info.id;
     let level = table_info.level;
+    toc.live_size += table_info_size(&table_info);
+    *toc.level_sizes.entry(level).or_insert(0) += table_info.file_size;
+    if level != 0 {
+        let keys: &mut BTreeMap<Buf, TableId> = toc.level_keys.entry(level).or_default();
+        let inserted: bool = keys.insert(table_info.smallest_key.clone(), table_id).is_none();
+        assert!(inserted);
+    }
     let inserted: bool = toc.table_infos.insert(table_id, table_info).is_none();
     assert!(inserted);
     let set: &mut BTreeSet<TableId> = toc.level_infos.entry(level).or_insert_with(|| BTreeSet::<TableId>::new());
@@ -92,10 +138,46 @@ fn add_table(toc: &mut Toc, table_info: TableInfo) {
     toc.next_table_id = toc.next_table_id.max(table_id.0 + 1);
 }
 
+// The table in the level (which mustn't be zero) whose key range holds the key, if any.
+pub fn table_for_key<'a>(toc: &'a Toc, level: LevelNumber, key: &[u8]) -> Option<&'a TableInfo> {
+    assert!(level != 0);
+    let keys: &BTreeMap<Buf, TableId> = toc.level_keys.get(&level)?;
+    // The last table starting at or before the key is the only one that could hold it.
+    let (_, id) = keys.range::<[u8], _>((Bound::Unbounded, Bound::Included(key))).next_back()?;
+    let ti: &TableInfo = toc.table_infos.get(id).expect("valid toc level keys");
+    return if key <= &ti.biggest_key[..] { Some(ti) } else { None };
+}
+
+// The tables in the level (which mustn't be zero) overlapping the interval, in key order.
+pub fn tables_in_interval<'a>(toc: &'a Toc, level: LevelNumber, interval: &Interval<Buf>) -> Vec<&'a TableInfo> {
+    assert!(level != 0);
+    let mut ret: Vec<&TableInfo> = Vec::new();
+    if let Some(keys) = toc.level_keys.get(&level) {
+        // Start with the last table starting at or before the lower bound, which might reach past it.
+        let start: Bound<&[u8]> = match ref_bound(&interval.lower) {
+            Bound::Unbounded => Bound::Unbounded,
+            Bound::Included(k) | Bound::Excluded(k) =>
+                match keys.range::<[u8], _>((Bound::Unbounded, Bound::Included(k))).next_back() {
+                    Some((smallest, _)) => Bound::Included(&smallest[..]),
+                    None => Bound::Unbounded,
+                },
+        };
+        for (smallest, id) in keys.range::<[u8], _>((start, Bound::Unbounded)) {
+            if !below_upper_bound(smallest, &interval.upper) {
+                break;
+            }
+            let ti: &TableInfo = toc.table_infos.get(id).expect("valid toc level keys");
+            if above_lower_bound(&ti.biggest_key, &interval.lower) {
+                ret.push(ti);
+            }
+        }
+    }
+    return ret;
+}
+
 fn encode_table_info(v: &mut Vec<u8>, ti: &TableInfo) {
     encode_uvarint(v, ti.id.0);
     encode_uvarint(v, ti.level);
-    encode_uvarint(v, ti.keys_offset);
     encode_uvarint(v, ti.file_size);
     encode_str(v, &ti.smallest_key);
     encode_str(v, &ti.biggest_key);
@@ -104,14 +186,12 @@ fn encode_table_info(v: &mut Vec<u8>, ti: &TableInfo) {
 fn decode_table_info(buf: &[u8], pos: &mut usize) -> Option<TableInfo> {
     let id: u64 = decode_uvarint(&buf, pos)?;
     let level: u64 = decode_uvarint(&buf, pos)?;
-    let keys_offset: u64 = decode_uvarint(&buf, pos)?;
     let file_size: u64 = decode_uvarint(&buf, pos)?;
     let smallest_key: Buf = decode_str(&buf, pos)?;
     let biggest_key: Buf = decode_str(&buf, pos)?;
     return Some(TableInfo{
         id: TableId(id),
         level: level,
-        keys_offset: keys_offset,
         file_size: file_size,
         smallest_key: smallest_key,
         biggest_key: biggest_key,
@@ -131,29 +211,17 @@ fn encode_entry(ent: &Entry) -> Vec<u8> {
         encode_table_info(&mut v, &table_info);
     }
 
-    let length: usize = v.len();
-    let checksum: u32 = crc::crc32::checksum_castagnoli(&v);
+    encode_uvarint(&mut v, ent.next_table_id);
+
     let mut ret = Vec::<u8>::new();
-    encode_u64(&mut ret, length as u64);
-    encode_u32(&mut ret, checksum);
-    ret.extend(v);
+    encode_checksummed(&mut ret, &v);
     return ret;
 }
 
-fn decode_entry(buf: &[u8], pos: &mut usize) -> Option<Entry> {
-    let length: usize = try_into_size(decode_u64(&buf, pos)?)?;
-    let checksum: u32 = decode_u32(&buf, pos)?;
-
-    let front = *pos;
-    if length > buf.len() - front {
-        return None;
-    }
-
-    let entry_slice = &buf[front..front+length];
-    let computed_checksum: u32 = crc::crc32::checksum_castagnoli(entry_slice);
-    if checksum != computed_checksum {
-        return None;
-    }
+fn decode_entry(whole_buf: &[u8], whole_pos: &mut usize) -> Option<Entry> {
+    let buf: &[u8] = observe_checksummed(whole_buf, whole_pos)?;
+    let mut pos_value: usize = 0;
+    let pos: &mut usize = &mut pos_value;
 
     let num_removals: usize = try_into_size(decode_uvarint(&buf, pos)?)?;
     let mut removals = Vec::<TableId>::new();
@@ -168,10 +236,12 @@ fn decode_entry(buf: &[u8], pos: &mut usize) -> Option<Entry> {
         additions.push(decode_table_info(&buf, pos)?);
     }
 
-    if *pos - front != length {
+    let next_table_id: u64 = decode_uvarint(&buf, pos)?;
+
+    if *pos != buf.len() {
         return None;
     }
-    return Some(Entry{removals, additions});
+    return Some(Entry{removals, additions, next_table_id});
 }
 
 fn process_entry(toc: &mut Toc, entry: Entry) -> fnv::FnvHashSet<TableId> {
@@ -186,6 +256,7 @@ fn process_entry(toc: &mut Toc, entry: Entry) -> fnv::FnvHashSet<TableId> {
         add_table(toc, addition);
         ret.remove(&table_id);
     }
+    toc.next_table_id = toc.next_table_id.max(entry.next_table_id);
     return ret;
 }
 
@@ -207,12 +278,12 @@ fn parse_tablefile_name(name: &str) -> Option<TableId> {
 // Returns map of table id to file size.
 fn read_dir_tables(dir: &str) -> Result<fnv::FnvHashMap<TableId, u64>> {
     let mut ret = fnv::FnvHashMap::default();
-    for entry_result in std::fs::read_dir(dir)? {
-        let ent = entry_result?;
+    for entry_result in std::fs::read_dir(dir).at_path(dir)? {
+        let ent = entry_result.at_path(dir)?;
         // Just looking for valid tab files, so we merely ignore non-unicode file names.
         if let Some(filename) = ent.file_name().to_str() {
             if let Some(table_id) = parse_tablefile_name(filename) {
-                let m = ent.metadata()?;
+                let m = ent.metadata().at_path(&table_filepath(dir, table_id))?;
                 if !m.is_file() {
                     return mk_err("non-file table file name");
                 }
@@ -224,39 +295,140 @@ fn read_dir_tables(dir: &str) -> Result<fnv::FnvHashMap<TableId, u64>> {
     return Ok(ret);
 }
 
+/// What `Store::open` did with files left over from table writes that never made it
+/// into the toc.  File names are relative to the store directory.
+#[derive(Clone, Debug, Default)]
+pub struct OrphanReport {
+    /// Partially written temp files, which were deleted.
+    pub removed_temp_files: Vec<String>,
+    /// Table files not referenced by the toc, which were deleted.
+    pub removed_tables: Vec<String>,
+    /// Table files not referenced by the toc, which were moved into `lost+found/`.
+    pub quarantined_tables: Vec<String>,
+}
+
+impl OrphanReport {
+    pub fn is_empty(&self) -> bool {
+        return self.removed_temp_files.is_empty() && self.removed_tables.is_empty()
+            && self.quarantined_tables.is_empty();
+    }
+}
+
+fn lost_found_dirname(dir: &str) -> String {
+    return format!("{}/lost+found", dir);
+}
+
+// Moves the file into lost+found, without clobbering anything quarantined there before.
+fn quarantine_file(dir: &str, filename: &str) -> Result<String> {
+    let lost_found: String = lost_found_dirname(dir);
+    if !std::path::Path::new(&lost_found).is_dir() {
+        std::fs::create_dir(&lost_found).at_path(&lost_found)?;
+    }
+    let mut dest_name: String = filename.to_string();
+    let mut n: u64 = 0;
+    while std::path::Path::new(&format!("{}/{}", lost_found, dest_name)).exists() {
+        n += 1;
+        dest_name = format!("{}.{}", filename, n);
+    }
+    let path: String = format!("{}/{}", dir, filename);
+    std::fs::rename(&path, format!("{}/{}", lost_found, dest_name)).at_path(&path)?;
+    sync_directory(&lost_found)?;
+    return Ok(format!("lost+found/{}", dest_name));
+}
+
+// Reconciles the table files in the directory with the toc:  temp files get deleted, and
+// so do table files the toc doesn't reference -- unless quarantine is set, in which case
+// they get moved into lost+found.  If read_toc truncated the toc, table files are left
+// alone, since we can't be sure what the lost part referenced.
+pub fn collect_orphans(dir: &str, toc: &Toc, toc_truncated: bool, quarantine: bool) -> Result<OrphanReport> {
+    let mut report = OrphanReport::default();
+
+    let mut orphans: Vec<TableId> = if toc_truncated {
+        Vec::new()
+    } else {
+        read_dir_tables(dir)?.keys().filter(|id| !toc.table_infos.contains_key(id)).cloned().collect()
+    };
+    orphans.sort();
+    for table_id in orphans {
+        let filename: String = table_filename(table_id);
+        if quarantine {
+            report.quarantined_tables.push(quarantine_file(dir, &filename)?);
+        } else {
+            let path: String = table_filepath(dir, table_id);
+            std::fs::remove_file(&path).at_path(&path)?;
+            report.removed_tables.push(filename);
+        }
+    }
+
+    for entry_result in std::fs::read_dir(dir).at_path(dir)? {
+        let ent = entry_result.at_path(dir)?;
+        if let Some(filename) = ent.file_name().to_str() {
+            if filename.ends_with(".tmp") && parse_tablefile_name(filename.split_at(filename.len() - 4).0).is_some() {
+                report.removed_temp_files.push(filename.to_string());
+            }
+        }
+    }
+    report.removed_temp_files.sort();
+    for filename in report.removed_temp_files.iter() {
+        let path: String = format!("{}/{}", dir, filename);
+        std::fs::remove_file(&path).at_path(&path)?;
+    }
+
+    if !report.is_empty() {
+        sync_directory(dir)?;
+    }
+    return Ok(report);
+}
+
 fn validate_toc(toc: &Toc, dirent_tables: &fnv::FnvHashMap<TableId, u64>) -> bool {
     return toc.table_infos.iter().all(|(id, info)|
         dirent_tables.get(id).map(|x| *x) == Some(info.file_size)
     );
 }
 
-pub fn read_toc(dir: &str) -> Result<(std::fs::File, Toc)> {
-    let mut f = std::fs::OpenOptions::new().read(true).append(true)
-        .open(toc_filename(dir))?;
+// Returns the toc file and its contents, and whether a torn entry at its end got truncated.
+pub fn read_toc(dir: &str) -> Result<(std::fs::File, Toc, bool)> {
+    let path: String = toc_filename(dir);
+    let mut f = std::fs::OpenOptions::new().read(true).append(true).open(&path).at_path(&path)?;
     let mut buf = Vec::<u8>::new();
-    f.read_to_end(&mut buf)?;
+    f.read_to_end(&mut buf).at_path(&path)?;
 
     if buf.get(0..8) != Some(&TOC_MAGIC) {
+        if buf.get(0..4) == Some(&TOC_MAGIC[0..4]) {
+            let mut pos: usize = 4;
+            let found: u32 = decode_u32(&buf, &mut pos).or_err("invalid toc header")?;
+            return Err(Error::VersionError{found: found, expected: TOC_VERSION});
+        }
         return mk_err("invalid toc header");
     }
 
     let mut toc = Toc{
         table_infos: fnv::FnvHashMap::default(),
         level_infos: BTreeMap::new(),
+        level_keys: BTreeMap::new(),
+        level_sizes: BTreeMap::new(),
         next_table_id: 0,
+        file_size: buf.len() as u64,
+        live_size: 0,
     };
 
     let mut pos: usize = TOC_MAGIC.len();
+    let mut truncated: bool = false;
     while pos < buf.len() {
         let savepos = pos;
         if let Some(entry) = decode_entry(&buf, &mut pos) {
             let _ = process_entry(&mut toc, entry);
-        } else {
-            f.set_len(savepos as u64)?;
+        } else if checksummed_runs_past_end(&buf, savepos) {
+            // A crash in the middle of an append.  Anything else is corruption.
+            f.set_len(savepos as u64).at_path(&path)?;
+            toc.file_size = savepos as u64;
+            truncated = true;
             // NOTE: It would be decent to seek to end (instead of past end),
             // even though not strictly necessary because we opened using
             // append(true).
-            return Ok((f, toc));
+            break;
+        } else {
+            return mk_err("corrupt toc entry");
         }
     }
 
@@ -264,12 +436,47 @@ pub fn read_toc(dir: &str) -> Result<(std::fs::File, Toc)> {
     if !validate_toc(&toc, &dirent_tables) {
         return mk_err("invalid toc");
     }
-    return Ok((f, toc));
+    return Ok((f, toc, truncated));
+}
+
+// Replaces the toc file with one holding a single entry that adds every live table.
+// The rewritten file forgets about removed tables, so the entry carries next_table_id,
+// which would otherwise go backwards if the biggest table id was removed.
+fn rewrite_toc(dir: &str, toc: &mut Toc, f: &mut std::fs::File) -> Result<()> {
+    let mut additions: Vec<TableInfo> = toc.table_infos.values().cloned().collect();
+    additions.sort_by_key(|ti| ti.id);
+    let mut data: Vec<u8> = TOC_MAGIC.to_vec();
+    data.extend(encode_entry(&Entry{removals: Vec::new(), additions: additions, next_table_id: toc.next_table_id}));
+
+    // We keep the temp file's handle, whose position is past the snapshot, for later
+    // appends.  Reopening the toc after the rename could fail and leave f pointing at
+    // the unlinked old file.
+    let tmp_path: String = toc_tmp_filename(dir);
+    let mut tmp = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true)
+        .open(&tmp_path).at_path(&tmp_path)?;
+    tmp.write_all(&data).at_path(&tmp_path)?;
+    tmp.sync_data().at_path(&tmp_path)?;
+    std::fs::rename(&tmp_path, toc_filename(dir)).at_path(&tmp_path)?;
+    *f = tmp;
+    toc.file_size = data.len() as u64;
+    sync_directory(dir)?;
+    return Ok(());
 }
 
 // Returns a list of table id's that can be removed.
-pub fn append_toc(toc: &mut Toc, f: &mut std::fs::File, entry: Entry) -> Result<fnv::FnvHashSet<TableId>> {
+pub fn append_toc(dir: &str, toc: &mut Toc, f: &mut std::fs::File, entry: Entry) -> Result<fnv::FnvHashSet<TableId>> {
     let data: Vec<u8> = encode_entry(&entry);
-    f.write_all(&data)?;
-    return Ok(process_entry(toc, entry));
+    let path: String = toc_filename(dir);
+    f.write_all(&data).at_path(&path)?;
+    f.sync_data().at_path(&path)?;
+    toc.file_size += data.len() as u64;
+    let ret = process_entry(toc, entry);
+
+    // The entry is committed, so a failed rewrite mustn't fail the append.  Until a
+    // rewrite renames over the toc, f and file_size still describe the old file, and
+    // the next append tries again.
+    if toc.file_size >= TOC_REWRITE_MIN_SIZE && toc.file_size / TOC_REWRITE_RATIO > toc.live_size {
+        let _ = rewrite_toc(dir, toc, f);
+    }
+    return Ok(ret);
 }
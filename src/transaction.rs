@@ -0,0 +1,77 @@
+use error::*;
+use options::*;
+use shared::SharedStore;
+use util::*;
+use Snapshot;
+use WriteBatch;
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+/// A read-modify-write on a `SharedStore`, begun with `SharedStore::begin`.
+///
+/// Reads see the store as it was when the transaction began, along with the transaction's
+/// own writes, which are buffered until `commit` applies them together.  If some other
+/// write has changed a key the transaction read since it began, `commit` fails with an
+/// `ErrorKind::Conflict` error and writes nothing, and the caller can retry with a new
+/// transaction.
+pub struct Transaction {
+    store: SharedStore,
+    snapshot: Snapshot,
+    // The keys read from the store, as opposed to from our own writes.
+    reads: BTreeSet<Buf>,
+    // The buffered writes, by key.  Later writes to a key replace earlier ones.
+    writes: BTreeMap<Buf, Mutation>,
+}
+
+impl Transaction {
+    pub(crate) fn begin(store: SharedStore) -> Transaction {
+        let snapshot: Snapshot = store.snapshot();
+        return Transaction{store: store, snapshot: snapshot, reads: BTreeSet::new(), writes: BTreeMap::new()};
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Buf>> {
+        if let Some(m) = self.writes.get(key) {
+            return Ok(match m {
+                &Mutation::Set(ref x) => Some(x.clone()),
+                &Mutation::Delete => None,
+            });
+        }
+        self.reads.insert(key.to_vec());
+        return self.store.get_opt(key, &ReadOptions{snapshot: Some(&self.snapshot)});
+    }
+
+    pub fn put(&mut self, key: &[u8], val: &[u8]) {
+        self.writes.insert(key.to_vec(), Mutation::Set(val.to_vec()));
+    }
+
+    /// Unlike `Store::remove`, this doesn't check whether the key is present.
+    pub fn remove(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), Mutation::Delete);
+    }
+
+    /// Applies the transaction's writes, as one `WriteBatch`, unless a key it read has
+    /// changed.  A transaction without writes always succeeds, since its reads all saw
+    /// the same state of the store.
+    pub fn commit(self) -> Result<()> {
+        return self.commit_opt(&WriteOptions::default());
+    }
+
+    /// Like `commit`, with the given write options.
+    pub fn commit_opt(self, opts: &WriteOptions) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::new();
+        for (key, m) in self.writes.iter() {
+            match m {
+                &Mutation::Set(ref val) => batch.put(key, val),
+                &Mutation::Delete => batch.remove(key),
+            }
+        }
+        self.store.make_room()?;
+        // Checking the reads and writing the batch happen under one lock, so no write
+        // can come between them.
+        return self.store.write().write_unless_changed(batch, &self.reads, &self.snapshot, opts);
+    }
+}
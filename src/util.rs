@@ -41,9 +41,44 @@ pub fn ref_bound(x: &Bound<Buf>) -> Bound<&[u8]> {
 
 pub fn table_filename(table_id: TableId) -> String { format!("{}.tab", table_id.0) }
 pub fn table_filepath(dir: &str, table_id: TableId) -> String { format!("{}/{}.tab", dir, table_id.0) }
+pub fn table_tmp_filepath(dir: &str, table_id: TableId) -> String { format!("{}/{}.tab.tmp", dir, table_id.0) }
 
 #[derive(Debug, Clone)]
 pub enum Mutation {
     Set(Buf),
     Delete,
 }
+
+// Every write gets the next sequence number, and so do the mutations it makes.  (A batch's
+// mutations all get the same one.)
+pub type SeqNum = u64;
+
+// A key's mutations, each with its sequence number, newest first.
+pub type Versions = Vec<(SeqNum, Mutation)>;
+
+// The newest of the versions that a reader at the snapshot can see.
+pub fn visible_version(versions: &[(SeqNum, Mutation)], snapshot: SeqNum) -> Option<&(SeqNum, Mutation)> {
+    return versions.iter().find(|&&(seq, _)| seq <= snapshot);
+}
+
+// Drops the versions that nobody can read:  readers of the latest state see the newest
+// version, and readers at a snapshot see the newest version at or before it, so we keep
+// those.  `snapshots` is the live snapshots, in ascending order.
+pub fn retain_readable(versions: &mut Versions, snapshots: &[SeqNum]) {
+    if versions.len() <= 1 {
+        return;
+    }
+    let mut newer: SeqNum = SeqNum::max_value();
+    let mut i: usize = 0;
+    versions.retain(|&(seq, _)| {
+        // The version is the newest at or before a snapshot iff some snapshot lies in
+        // [seq, newer).
+        let keep: bool = i == 0 || match snapshots.binary_search(&seq) {
+            Ok(_) => true,
+            Err(j) => j < snapshots.len() && snapshots[j] < newer,
+        };
+        newer = seq;
+        i += 1;
+        keep
+    });
+}
@@ -0,0 +1,190 @@
+use disk;
+use encoding::*;
+use error::*;
+use memstore::*;
+use util::*;
+
+use std;
+use std::io::Read;
+use std::io::Seek;
+use std::io::Write;
+
+/* .log file format:
+
+    [record][record]...[record]
+
+[record] format:
+
+    [u64][u32][u64][varint][str][mutation]...[str][mutation]
+    L     C    S    N       K    M              K    M
+
+    where L is the length of what follows C, C is its checksum, S is the sequence number of
+    the mutations, and N is the number of mutations, each a key K and a mutation M, encoded
+    like in a .tab file.  A record holds a whole WriteBatch (or a single put or remove), so
+    a crash can't leave part of a batch behind.
+
+Every mutation applied to the active memstore is first appended to the current log.  When the
+memstores get flushed, we start a new log, and once the flushed tables are recorded in the toc,
+the old logs are deleted.  On open, all logs are replayed in order.
+*/
+
+pub type LogNumber = u64;
+
+pub struct Wal {
+    file: std::fs::File,
+    path: String,
+    pub number: LogNumber,
+    // Number of records appended since the last fsync.
+    pub unsynced: usize,
+    // The length of the whole records written so far.
+    len: u64,
+    // Set if a failed append left part of a record behind that we couldn't cut off.
+    // Anything appended after it would get lost on replay, so appends fail from then on.
+    broken: Option<Error>,
+}
+
+fn log_filename(number: LogNumber) -> String { format!("{}.log", number) }
+fn log_filepath(dir: &str, number: LogNumber) -> String { format!("{}/{}.log", dir, number) }
+
+fn parse_logfile_name(name: &str) -> Option<LogNumber> {
+    if !name.ends_with(".log") {
+        return None;
+    }
+    let frontpart: &str = name.split_at(name.len() - 4).0;
+    if let Some(x) = frontpart.parse::<u64>().ok() {
+        // Like with table files, make sure "01.log" doesn't count as log 1.
+        if log_filename(x) == name {
+            return Some(x);
+        }
+    }
+    return None;
+}
+
+// Returns the log numbers in the directory, in ascending order.
+fn read_dir_logs(dir: &str) -> Result<Vec<LogNumber>> {
+    let mut ret = Vec::new();
+    for entry_result in std::fs::read_dir(dir).at_path(dir)? {
+        let ent = entry_result.at_path(dir)?;
+        if let Some(filename) = ent.file_name().to_str() {
+            if let Some(number) = parse_logfile_name(filename) {
+                ret.push(number);
+            }
+        }
+    }
+    ret.sort();
+    return Ok(ret);
+}
+
+fn encode_record(seq: SeqNum, ops: &[(Buf, Mutation)]) -> Vec<u8> {
+    let mut v = Vec::<u8>::new();
+    encode_u64(&mut v, seq);
+    encode_uvarint(&mut v, ops.len() as u64);
+    for &(ref key, ref m) in ops {
+        encode_str(&mut v, key);
+        disk::encode_mutation(&mut v, m);
+    }
+    let mut ret = Vec::<u8>::new();
+    encode_checksummed(&mut ret, &v);
+    return ret;
+}
+
+fn decode_record(buf: &[u8], pos: &mut usize) -> Option<(SeqNum, Vec<(Buf, Mutation)>)> {
+    let payload: &[u8] = observe_checksummed(buf, pos)?;
+    let mut p: usize = 0;
+    let seq: SeqNum = decode_u64(payload, &mut p)?;
+    let count: u64 = decode_uvarint(payload, &mut p)?;
+    let mut ops = Vec::<(Buf, Mutation)>::new();
+    for _ in 0..count {
+        let key: Buf = decode_str(payload, &mut p)?;
+        let m: Mutation = disk::decode_mutation(payload, &mut p)?;
+        ops.push((key, m));
+    }
+    if p != payload.len() {
+        return None;
+    }
+    return Some((seq, ops));
+}
+
+// Replays the log into ms, raising last_seq to the records' sequence numbers.  A bad record
+// at the end of the last log is what a crash mid-append looks like, so it gets truncated.
+// Anywhere else, it's corruption.
+fn replay_log(dir: &str, number: LogNumber, is_last: bool, ms: &mut MemStore, last_seq: &mut SeqNum) -> Result<()> {
+    let path: String = log_filepath(dir, number);
+    let mut f = std::fs::OpenOptions::new().read(true).write(true).open(&path).at_path(&path)?;
+    let mut buf = Vec::<u8>::new();
+    f.read_to_end(&mut buf).at_path(&path)?;
+
+    let mut pos: usize = 0;
+    while pos < buf.len() {
+        let savepos = pos;
+        if let Some((seq, ops)) = decode_record(&buf, &mut pos) {
+            for (key, m) in ops {
+                // Nobody has a snapshot yet, so only the newest versions matter.
+                ms.apply(key, seq, m, &[]);
+            }
+            *last_seq = (*last_seq).max(seq);
+        } else if is_last {
+            f.set_len(savepos as u64).at_path(&path)?;
+            return Ok(());
+        } else {
+            return mk_err("corrupt log");
+        }
+    }
+    return Ok(());
+}
+
+// Returns the numbers of the existing logs, a memstore holding their contents, and the
+// largest sequence number in them (or zero).
+pub fn recover_logs(dir: &str) -> Result<(Vec<LogNumber>, MemStore, SeqNum)> {
+    let numbers: Vec<LogNumber> = read_dir_logs(dir)?;
+    let mut ms = MemStore::new();
+    let mut last_seq: SeqNum = 0;
+    for (i, &number) in numbers.iter().enumerate() {
+        replay_log(dir, number, i + 1 == numbers.len(), &mut ms, &mut last_seq)?;
+    }
+    return Ok((numbers, ms, last_seq));
+}
+
+pub fn create_log(dir: &str, number: LogNumber) -> Result<Wal> {
+    let path: String = log_filepath(dir, number);
+    let file = std::fs::OpenOptions::new().write(true).create_new(true).open(&path).at_path(&path)?;
+    // Syncing the log is no use if its directory entry could get lost.
+    disk::sync_directory(dir)?;
+    return Ok(Wal{file: file, path: path, number: number, unsynced: 0, len: 0, broken: None});
+}
+
+pub fn remove_log(dir: &str, number: LogNumber) -> Result<()> {
+    let path: String = log_filepath(dir, number);
+    std::fs::remove_file(&path).at_path(&path)?;
+    return Ok(());
+}
+
+impl Wal {
+    pub fn append(&mut self, seq: SeqNum, ops: &[(Buf, Mutation)]) -> Result<()> {
+        if let Some(ref e) = self.broken {
+            return Err(e.clone());
+        }
+        // A crash mid-write leaves a torn record at the end of the log, which replay cuts
+        // off.  A failed write (say, from a full disk) can leave one too, but the process
+        // lives on, so we cut it off here, before anything gets appended after it.
+        let data: Vec<u8> = encode_record(seq, ops);
+        if let Err(e) = self.file.write_all(&data) {
+            let len: u64 = self.len;
+            let restored = self.file.set_len(len).and_then(|_| self.file.seek(std::io::SeekFrom::Start(len)));
+            let err = Error::IoError(e, Some(self.path.clone()));
+            if restored.is_err() {
+                self.broken = Some(err.clone());
+            }
+            return Err(err);
+        }
+        self.len += data.len() as u64;
+        self.unsynced += 1;
+        return Ok(());
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_data().at_path(&self.path)?;
+        self.unsynced = 0;
+        return Ok(());
+    }
+}